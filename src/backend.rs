@@ -0,0 +1,52 @@
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+use crate::{
+	error::DoitError,
+	generator::{CppBackend, shell::ShellBackend},
+	program::Program,
+};
+
+/**
+Turns a validated [`Program`] into a project of generated files. The program has already been checked, so a backend
+only reports the errors that are specific to its own output.
+*/
+pub trait Backend {
+	/** The name that selects the backend, as in `doit --emit <name>` */
+	fn name(&self) -> &'static str;
+	fn emit(&self, program: &Program) -> Result<Project, DoitError>;
+}
+
+/** The files generated by a [`Backend`], with paths relative to the directory they are written to. */
+#[derive(Default)]
+pub struct Project {
+	pub files: Vec<(PathBuf, String)>,
+}
+
+impl Project {
+	pub fn single(path: &str, contents: String) -> Project {
+		Project { files: vec![(PathBuf::from(path), contents)] }
+	}
+
+	pub fn write(&self, directory: &Path) -> Result<(), DoitError> {
+		for (path, contents) in &self.files {
+			let path = directory.join(path);
+			if let Some(parent) = path.parent() {
+				fs::create_dir_all(parent)?;
+			}
+			fs::write(path, contents)?;
+		}
+		Ok(())
+	}
+}
+
+/** The backends that ship with doit. */
+pub fn builtin() -> Vec<Box<dyn Backend>> {
+	vec![Box::new(CppBackend), Box::new(ShellBackend)]
+}
+
+pub fn find(name: &str) -> Option<Box<dyn Backend>> {
+	builtin().into_iter().find(|backend| backend.name() == name)
+}
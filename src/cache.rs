@@ -0,0 +1,322 @@
+use std::{
+	env,
+	fs::{self, File, TryLockError},
+	io,
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+	generator::sources::{DOIT_HEADER, RUNTIME_SOURCE, SOURCE_FILE},
+	utils::{hash::calculate_hash, log},
+};
+
+const MANIFEST_FILENAME: &str = "manifest";
+const USED_FILENAME: &str = "used";
+const LOCK_FILENAME: &str = "lock";
+/** Overrides the cache root. A relative path is resolved beside the do.it file (ie. `.doit`). */
+pub const CACHE_DIR_ENV: &str = "DOIT_CACHE_DIR";
+/** Bumped whenever the fields that make up the cache key change. */
+const CACHE_KEY_VERSION: &str = "1";
+
+/**
+Computes the key that decides whether a cached binary can be reused. It is a stable FNV-1a hash over:
+- the version of the cache key itself
+- the doit version
+- the C++ runtime that the source is embedded into and linked with
+- the compiler and every flag passed to it
+- the do.it source (the language has no includes, so this is the only input file)
+
+A change in any of them produces a different key, and so a rebuild.
+*/
+pub fn cache_key(source: &str, compiler: &[String]) -> String {
+	let mut fields = vec![CACHE_KEY_VERSION, env!("CARGO_PKG_VERSION"), DOIT_HEADER, RUNTIME_SOURCE, SOURCE_FILE];
+	fields.push("\0compiler");
+	fields.extend(compiler.iter().map(String::as_str));
+	fields.push("\0source");
+	fields.push(source);
+	format!("{:016x}", calculate_hash(&fields))
+}
+
+/**
+Directory of the runtime object file that every targets binary links with. It is shared by all do.it files, and only
+differs between doit versions and compilers, so it is rebuilt far less often than the targets themselves.
+*/
+pub fn runtime_directory(root: &Path, compiler: &[String]) -> PathBuf {
+	let mut fields = vec![CACHE_KEY_VERSION, env!("CARGO_PKG_VERSION"), DOIT_HEADER, RUNTIME_SOURCE];
+	fields.extend(compiler.iter().map(String::as_str));
+	root.join(format!("runtime-{:016x}", calculate_hash(&fields)))
+}
+
+/**
+Finds the directory that holds the caches of every do.it file: `$DOIT_CACHE_DIR` if set, otherwise
+`$XDG_CACHE_HOME/doit` and finally `~/.cache/doit`. If none of them are available, the cache is kept beside the
+do.it file in `.doit`.
+*/
+pub fn cache_root(filename: &str) -> PathBuf {
+	let beside = |path: &str| Path::new(filename).parent().unwrap_or(Path::new(".")).join(path);
+	let non_empty = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+	if let Some(root) = non_empty(CACHE_DIR_ENV) {
+		return if Path::new(&root).is_absolute() { PathBuf::from(root) } else { beside(&root) };
+	}
+	if let Some(xdg) = non_empty("XDG_CACHE_HOME").filter(|xdg| Path::new(xdg).is_absolute()) {
+		return Path::new(&xdg).join("doit");
+	}
+	match non_empty("HOME") {
+		Some(home) => Path::new(&home).join(".cache").join("doit"),
+		None => beside(".doit"),
+	}
+}
+
+/** The cache directory of a single do.it file, which is named after the hash of its absolute path. */
+pub fn cache_directory(root: &Path, filename: &str) -> PathBuf {
+	root.join(format!("{:016x}", calculate_hash(&[filename])))
+}
+
+/**
+Takes an exclusive lock on the cache directory, waiting for any other doit process that holds it. The lock is released
+when the returned file is dropped. The flag is true when another process held the lock, and so may have just rebuilt
+the cache.
+*/
+pub fn lock(directory: &str) -> io::Result<(File, bool)> {
+	let file = File::create(Path::new(directory).join(LOCK_FILENAME))?;
+	match file.try_lock() {
+		Ok(()) => Ok((file, false)),
+		Err(TryLockError::WouldBlock) => {
+			log::info("Waiting for another doit process to finish compiling...");
+			file.lock()?;
+			Ok((file, true))
+		}
+		Err(TryLockError::Error(err)) => Err(err),
+	}
+}
+
+/** Records that the cache directory was just used, which keeps it safe from `doit --gc` for a while. */
+pub fn mark_used(directory: &str) -> io::Result<()> {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+	fs::write(Path::new(directory).join(USED_FILENAME), now.to_string())
+}
+
+fn last_used(directory: &Path) -> Option<SystemTime> {
+	match fs::read_to_string(directory.join(USED_FILENAME)) {
+		Ok(used) => Some(UNIX_EPOCH + Duration::from_secs(used.trim().parse().ok()?)),
+		Err(_) => fs::metadata(directory).and_then(|meta| meta.modified()).ok(),
+	}
+}
+
+/**
+Whether a directory under the cache root was created by doit: either the cache of a do.it file, which is named after
+a hash and holds a manifest, used marker or lock (which is all that a first compile that failed leaves behind), or a
+runtime directory. `DOIT_CACHE_DIR` may point anywhere, so nothing
+else in the root is ever removed.
+*/
+fn is_cache(directory: &Path) -> bool {
+	let name = directory.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+	let is_hash = |hash: &str| hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit());
+	if !directory.is_dir() {
+		return false;
+	}
+	match name.strip_prefix("runtime-") {
+		Some(hash) => is_hash(hash),
+		None => {
+			let markers = [MANIFEST_FILENAME, USED_FILENAME, LOCK_FILENAME];
+			is_hash(name) && markers.iter().any(|marker| directory.join(marker).exists())
+		}
+	}
+}
+
+/** The doit cache directories under `root`, see [`is_cache`]. */
+fn caches(root: &Path) -> io::Result<Vec<PathBuf>> {
+	if !root.exists() {
+		return Ok(vec![]);
+	}
+	let mut caches = vec![];
+	for entry in fs::read_dir(root)? {
+		let directory = entry?.path();
+		if is_cache(&directory) {
+			caches.push(directory);
+		}
+	}
+	Ok(caches)
+}
+
+/** Removes every cache directory under `root`, leaving the root itself and anything else in it. */
+pub fn clean_all(root: &Path) -> io::Result<Vec<PathBuf>> {
+	let caches = caches(root)?;
+	for directory in &caches {
+		fs::remove_dir_all(directory)?;
+	}
+	Ok(caches)
+}
+
+/**
+Removes every cache directory under `root` whose do.it file no longer exists, or which has not been used for
+`days` days. Returns the removed directories.
+*/
+pub fn collect_garbage(root: &Path, days: u64) -> io::Result<Vec<PathBuf>> {
+	let mut removed = vec![];
+	let cutoff = SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+	for directory in caches(root)? {
+		let orphaned = Manifest::load(directory.to_str().unwrap_or_default())
+			.is_some_and(|manifest| !Path::new(&manifest.source).exists());
+		let stale = last_used(&directory).is_none_or(|used| used < cutoff);
+		if orphaned || stale {
+			fs::remove_dir_all(&directory)?;
+			removed.push(directory);
+		}
+	}
+	Ok(removed)
+}
+
+/**
+Describes the do.it source that the `targets` binary in a cache directory was compiled from. It is only ever written
+after a successful compile, so a matching manifest guarantees that the binary beside it is up to date.
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct Manifest {
+	pub hash: String,
+	pub version: String,
+	/** Absolute path of the do.it file, used by `doit --gc` to find caches that have been orphaned */
+	pub source: String,
+}
+
+impl Manifest {
+	pub fn new(hash: &str, source: &str) -> Manifest {
+		Manifest { hash: hash.to_string(), version: env!("CARGO_PKG_VERSION").to_string(), source: source.to_string() }
+	}
+
+	/** Reads the manifest from the cache directory, treating a missing or unreadable manifest as no manifest at all. */
+	pub fn load(directory: &str) -> Option<Manifest> {
+		Manifest::parse(&fs::read_to_string(Path::new(directory).join(MANIFEST_FILENAME)).ok()?)
+	}
+
+	/** Writes the manifest to a temporary file and renames it into place, so readers never see a partial manifest. */
+	pub fn store(&self, directory: &str) -> io::Result<()> {
+		let path = Path::new(directory).join(MANIFEST_FILENAME);
+		let temp = Path::new(directory).join(format!("{MANIFEST_FILENAME}.{}.tmp", std::process::id()));
+		fs::write(&temp, self.to_string())?;
+		fs::rename(&temp, path).inspect_err(|_| {
+			let _ = fs::remove_file(&temp);
+		})
+	}
+
+	fn parse(text: &str) -> Option<Manifest> {
+		let (mut hash, mut version, mut source) = (None, None, None);
+		for line in text.lines() {
+			match line.split_once('=') {
+				Some(("hash", value)) => hash = Some(value.to_string()),
+				Some(("version", value)) => version = Some(value.to_string()),
+				Some(("source", value)) => source = Some(value.to_string()),
+				_ => {}
+			}
+		}
+		Some(Manifest { hash: hash?, version: version?, source: source? })
+	}
+
+	/** True when the manifest matches and the binary it describes still exists. */
+	pub fn is_fresh(&self, directory: &str) -> bool {
+		Manifest::load(directory).as_ref() == Some(self) && Path::new(directory).join("targets").exists()
+	}
+}
+
+impl std::fmt::Display for Manifest {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		writeln!(f, "hash={}", self.hash)?;
+		writeln!(f, "version={}", self.version)?;
+		writeln!(f, "source={}", self.source)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		env,
+		fs::{self, File},
+		time::UNIX_EPOCH,
+	};
+
+	use super::{Manifest, cache_key, clean_all, collect_garbage, lock, mark_used};
+
+	#[test]
+	fn test_cache_key() {
+		let command = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+		let key = cache_key("a: $ echo", &command(&["g++", "--std=c++20"]));
+		assert_eq!(key.len(), 16);
+		assert_eq!(key, cache_key("a: $ echo", &command(&["g++", "--std=c++20"])));
+		assert_ne!(key, cache_key("a: $ echo ", &command(&["g++", "--std=c++20"])));
+		assert_ne!(key, cache_key("a: $ echo", &command(&["g++", "--std=c++20", "-O2"])));
+		assert_ne!(key, cache_key("a: $ echo", &command(&["clang++", "--std=c++20"])));
+	}
+
+	#[test]
+	fn test_manifest() {
+		let directory = env::temp_dir().join(format!("doit-manifest-{}", std::process::id()));
+		let directory = directory.to_str().unwrap();
+		fs::create_dir_all(directory).unwrap();
+		let manifest = Manifest::new("1234", "/tmp/do.it");
+		assert_eq!(Manifest::load(directory), None);
+		manifest.store(directory).unwrap();
+		assert_eq!(Manifest::load(directory), Some(manifest.clone()));
+		assert!(!manifest.is_fresh(directory));
+		fs::write(format!("{directory}/targets"), "").unwrap();
+		assert!(manifest.is_fresh(directory));
+		assert!(!Manifest::new("5678", "/tmp/do.it").is_fresh(directory));
+		assert_eq!(Manifest::parse("garbage"), None);
+		fs::remove_dir_all(directory).unwrap();
+	}
+
+	#[test]
+	fn test_collect_garbage() {
+		let root = env::temp_dir().join(format!("doit-gc-{}", std::process::id()));
+		let orphaned = root.join("000000000000000a");
+		let (stale, fresh) = (root.join("000000000000000b"), root.join("000000000000000c"));
+		for directory in [&orphaned, &stale, &fresh] {
+			fs::create_dir_all(directory).unwrap();
+			mark_used(directory.to_str().unwrap()).unwrap();
+		}
+		// Directories that doit did not create are left alone, however old they are
+		let (foreign, unmarked) = (root.join("photos"), root.join("000000000000000d"));
+		for directory in [&foreign, &unmarked] {
+			fs::create_dir_all(directory).unwrap();
+			fs::write(directory.join("keep"), "").unwrap();
+		}
+		// A do.it file whose first compile failed only leaves its lock and source behind, and so is stale as of then
+		let failed = root.join("000000000000000e");
+		fs::create_dir_all(&failed).unwrap();
+		lock(failed.to_str().unwrap()).unwrap();
+		fs::write(failed.join("targets.cpp"), "").unwrap();
+		File::open(&failed).unwrap().set_modified(UNIX_EPOCH).unwrap();
+		let source = root.join("do.it");
+		fs::write(&source, "").unwrap();
+		Manifest::new("1", "/does/not/exist/do.it").store(orphaned.to_str().unwrap()).unwrap();
+		Manifest::new("2", source.to_str().unwrap()).store(stale.to_str().unwrap()).unwrap();
+		Manifest::new("3", source.to_str().unwrap()).store(fresh.to_str().unwrap()).unwrap();
+		fs::write(stale.join("used"), "0").unwrap();
+
+		let mut removed = collect_garbage(&root, 30).unwrap();
+		removed.sort();
+		assert_eq!(removed, vec![orphaned, stale, failed]);
+		assert!(fresh.exists());
+		let runtime = root.join("runtime-00000000000000ff");
+		fs::create_dir_all(&runtime).unwrap();
+		let mut removed = clean_all(&root).unwrap();
+		removed.sort();
+		assert_eq!(removed, vec![fresh, runtime]);
+		assert!(foreign.join("keep").exists());
+		assert!(unmarked.join("keep").exists());
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn test_lock() {
+		let directory = env::temp_dir().join(format!("doit-lock-{}", std::process::id()));
+		let directory = directory.to_str().unwrap();
+		fs::create_dir_all(directory).unwrap();
+		let (held, waited) = lock(directory).unwrap();
+		assert!(!waited);
+		assert!(fs::File::create(format!("{directory}/lock")).unwrap().try_lock().is_err());
+		drop(held);
+		assert!(fs::File::create(format!("{directory}/lock")).unwrap().try_lock().is_ok());
+		fs::remove_dir_all(directory).unwrap();
+	}
+}
@@ -1,18 +1,24 @@
 use std::{
 	fs::{self, File},
-	hash::{DefaultHasher, Hash, Hasher},
-	io::{Error, Read, Write},
-	path::Path,
-	process::Command,
+	io::Write,
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+	time::{Duration, Instant},
 };
 
 use crate::{
-	generator::Generator,
+	cache::{self, Manifest, cache_key},
+	diagnostics::{self, Diagnostic, Severity, codes},
+	error::DoitError,
+	backend,
+	generator::{CppBackend, sources},
 	lexer::{
 		Lexer,
-		token::{TokenType, Tokenizer},
+		token::{Span, TokenType, Tokenizer},
 	},
 	parser::{Parser, nodes::NodeType},
+	program::Program,
+	toolchain::Toolchain,
 	utils::log,
 };
 
@@ -24,103 +30,354 @@ pub enum CompileMode {
 	PRINT_NODES,
 	PRINT_SOURCE,
 	PRINT_TARGETS,
+	/** Prints the project generated by the backend with the given name */
+	EMIT(String),
 }
 
-pub fn build(directory: &String, filename: &str, keep: bool, force: bool, mode: CompileMode) -> Result<(), Error> {
-	let mut source = String::new();
-	{
-		let mut file = File::open(filename)?;
-		file.read_to_string(&mut source)?;
+pub fn build(
+	directory: &String,
+	filename: &str,
+	keep: bool,
+	force: bool,
+	timings: bool,
+	mode: CompileMode,
+) -> Result<(), DoitError> {
+	let source = fs::read_to_string(filename)?;
+	match mode {
+		CompileMode::PRINT_TOKENS => print_tokens(&mut Lexer::new(filename, &source)),
+		CompileMode::PRINT_NODES => print_nodes(&mut Parser::new(&mut Lexer::new(filename, &source))),
+		CompileMode::PRINT_TARGETS => print_targets(&mut Parser::new(&mut Lexer::new(filename, &source))),
+		CompileMode::PRINT_SOURCE => generate(filename, &source).map(|cpp_source| println!("{cpp_source}")),
+		CompileMode::EMIT(name) => emit(filename, &source, &name),
+		CompileMode::NORMAL => {
+			let mut timings = Timings::new(timings);
+			let result = build_cached(directory, filename, &source, keep, force, &mut timings);
+			timings.report();
+			result
+		}
+	}
+	.map_err(|err| report(filename, &source, err))
+}
+
+/** Compiles the do.it source into the cache directory, unless the binary there was already built from it. */
+fn build_cached(
+	directory: &String,
+	filename: &str,
+	source: &str,
+	keep: bool,
+	force: bool,
+	timings: &mut Timings,
+) -> Result<(), DoitError> {
+	let toolchain = Toolchain::from_env()?;
+	let manifest = Manifest::new(&cache_key(source, &toolchain.command()), filename);
+	if !force && manifest.is_fresh(directory) {
+		timings.skip("targets", "up to date");
+		return Ok(cache::mark_used(directory)?);
 	}
 
 	fs::create_dir_all(directory)?;
+	let (_lock, waited) = cache::lock(directory)?;
+	// Another process may have rebuilt the binary while we were waiting on the lock
+	if (!force || waited) && manifest.is_fresh(directory) {
+		timings.skip("targets", "up to date");
+		return Ok(cache::mark_used(directory)?);
+	}
+	let result = timings
+		.measure("probe", || toolchain.probe())
+		.and_then(|_| timings.measure("generate", || generate(filename, source)))
+		.and_then(|cpp_source| {
+			let root = Path::new(directory).parent().unwrap_or(Path::new("."));
+			let runtime = build_runtime(&toolchain, root, keep, timings)?;
+			let binary = directory.to_owned() + "/targets";
+			timings.measure("targets", || compile(&toolchain, directory, filename, source, &cpp_source, &runtime, &binary, &[]))
+		});
+	if !keep {
+		let _ = fs::remove_file(directory.to_owned() + "/targets.cpp");
+	}
+	result?;
+	// Only record the new source once the binary has actually been rebuilt from it
+	manifest.store(directory)?;
+	Ok(cache::mark_used(directory)?)
+}
 
-	let mut hasher = DefaultHasher::new();
-	source.hash(&mut hasher);
-	let new_hash = hasher.finish();
-	if !force
-		&& Path::new(&(directory.to_owned() + "/hash")).exists()
-		&& Path::new(&(directory.to_owned() + "/targets")).exists()
-	{
-		let old_hash = fs::read_to_string(directory.to_owned() + "/hash")?;
-		if old_hash == new_hash.to_string() {
-			return Ok(());
-		}
+/**
+Copies the compiled targets out of the cache to `destination`, so that they can be shipped without doit. A static
+export is linked on its own with `-static`, since the cached binary links the C++ runtime dynamically.
+*/
+pub fn export(directory: &String, filename: &str, destination: &Path, static_link: bool) -> Result<(), DoitError> {
+	let source = fs::read_to_string(filename)?;
+	if let Some(parent) = destination.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+		fs::create_dir_all(parent)?;
+	}
+	if !static_link {
+		let mut timings = Timings::new(false);
+		build_cached(directory, filename, &source, false, false, &mut timings).map_err(|err| report(filename, &source, err))?;
+		fs::copy(Path::new(directory).join("targets"), destination)?;
+		return Ok(());
 	}
-	fs::write(directory.to_owned() + "/hash", new_hash.to_string())?;
+	let toolchain = Toolchain::from_env()?;
+	fs::create_dir_all(directory)?;
+	let (_lock, _) = cache::lock(directory)?;
+	let result = toolchain.probe().and_then(|_| generate(filename, &source)).and_then(|cpp_source| {
+		let root = Path::new(directory).parent().unwrap_or(Path::new("."));
+		let runtime = build_runtime(&toolchain, root, false, &mut Timings::new(false))?;
+		let output = destination.to_string_lossy();
+		compile(&toolchain, directory, filename, &source, &cpp_source, &runtime, &output, &["-static"])
+	});
+	let _ = fs::remove_file(directory.to_owned() + "/targets.cpp");
+	result.map_err(|err| report(filename, &source, err))
+}
 
-	match mode {
-		CompileMode::PRINT_TOKENS => print_tokens(&mut Lexer::new(filename, &source)),
-		CompileMode::PRINT_NODES => print_nodes(&mut Parser::new(&mut Lexer::new(filename, &source))),
-		CompileMode::PRINT_TARGETS => print_targets(&mut Parser::new(&mut Lexer::new(filename, &source))),
-		_ => {
-			let mut lexer = Lexer::new(filename, &source);
-			let mut parser = Parser::new(&mut lexer);
-			let source = Generator::new(&mut parser).generate()?;
-			if mode == CompileMode::PRINT_SOURCE {
-				println!("{source}");
-			} else {
-				compile(directory, &source)?;
-			}
+/** Compiles the shared runtime into an object file, unless this doit version and compiler have already built it. */
+fn build_runtime(toolchain: &Toolchain, root: &Path, keep: bool, timings: &mut Timings) -> Result<PathBuf, DoitError> {
+	let directory = cache::runtime_directory(root, &toolchain.command());
+	let object = directory.join("runtime.o");
+	let directory_str = directory.to_string_lossy().to_string();
+	if !object.exists() {
+		fs::create_dir_all(&directory)?;
+		let (_lock, _) = cache::lock(&directory_str)?;
+		if !object.exists() {
+			timings.measure("runtime", || compile_runtime(toolchain, &directory, keep))?;
+		} else {
+			timings.skip("runtime", "cached");
 		}
+	} else {
+		timings.skip("runtime", "cached");
 	}
+	cache::mark_used(&directory_str)?;
+	Ok(object)
+}
 
+fn compile_runtime(toolchain: &Toolchain, directory: &Path, keep: bool) -> Result<(), DoitError> {
+	let cpp = directory.join("runtime.cpp");
+	let temp = directory.join("runtime.o.tmp");
+	fs::write(&cpp, sources::DOIT_HEADER.to_string() + sources::RUNTIME_SOURCE)?;
+	let output = Command::new(&toolchain.compiler)
+		.args(toolchain.args())
+		.arg("-c")
+		.arg(&cpp)
+		.arg("-o")
+		.arg(&temp)
+		.stdout(Stdio::inherit())
+		.stderr(Stdio::piped())
+		.output()
+		.map_err(|err| DoitError::Toolchain(format!("Failed to run the C++ compiler '{}': {err}", toolchain.compiler)))?;
 	if !keep {
-		let _ = fs::remove_file(directory.to_owned() + "/targets.cpp");
+		let _ = fs::remove_file(&cpp);
+	}
+	if !output.status.success() {
+		let _ = fs::remove_file(&temp);
+		// The runtime is not generated from the do.it file, so a failure here is down to the compiler
+		return Err(DoitError::Toolchain(format!(
+			"The C++ compiler '{}' failed to compile the doit runtime:\n{}",
+			toolchain.compiler,
+			String::from_utf8_lossy(&output.stderr).trim_end()
+		)));
 	}
+	fs::rename(&temp, directory.join("runtime.o"))?;
 	Ok(())
 }
 
-fn compile(directory: &String, source: &str) -> Result<(), Error> {
+fn generate(filename: &str, source: &str) -> Result<String, DoitError> {
+	CppBackend::generate(&Program::parse(filename, source)?)
+}
+
+fn emit(filename: &str, source: &str, name: &str) -> Result<(), DoitError> {
+	let Some(backend) = backend::find(name) else {
+		let diagnostic = Diagnostic::error(codes::INTERNAL, &format!("Unknown backend '{name}'"), Span::EMPTY);
+		return Err(DoitError::Codegen(vec![diagnostic]));
+	};
+	let project = backend.emit(&Program::parse(filename, source)?)?;
+	project.files.iter().for_each(|(_, contents)| print!("{contents}"));
+	Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compile(
+	toolchain: &Toolchain,
+	directory: &String,
+	filename: &str,
+	source: &str,
+	cpp_source: &str,
+	runtime: &Path,
+	output: &str,
+	link_args: &[&str],
+) -> Result<(), DoitError> {
 	let cpp = &(directory.to_owned() + "/targets.cpp");
 	{
 		let mut file = File::create(cpp)?;
-		file.write_all(source.as_bytes())?;
+		file.write_all(cpp_source.as_bytes())?;
 		file.flush()?;
 	}
 
-	let mut output = Command::new("g++")
-		.args(["--std=c++20", cpp, "-o", &(directory.to_owned() + "/targets")])
-		.spawn()?;
-	if !output.wait()?.success() {
-		return Err(Error::other("Failed to compile"));
+	// Build beside the binary and rename it into place, so a running or concurrent reader never sees a partial file
+	let binary = output.to_owned() + ".tmp";
+	let destination = output;
+	let output = Command::new(&toolchain.compiler)
+		.args(toolchain.args())
+		.arg(cpp)
+		.arg(runtime)
+		.args(link_args)
+		.args(["-o", &binary])
+		.stdout(Stdio::inherit())
+		.stderr(Stdio::piped())
+		.output()
+		.map_err(|err| DoitError::Toolchain(format!("Failed to run the C++ compiler '{}': {err}", toolchain.compiler)))?;
+	let diagnostics = map_compile_output(filename, source, &String::from_utf8_lossy(&output.stderr));
+	if !output.status.success() {
+		let _ = fs::remove_file(&binary);
+		if diagnostics.is_empty() {
+			let diagnostic = Diagnostic::error(codes::CPP_COMPILE, "Failed to compile the generated C++", Span::EMPTY);
+			return Err(DoitError::Codegen(vec![diagnostic]));
+		}
+		return Err(DoitError::Codegen(diagnostics));
 	}
+	diagnostics::emit(filename, source, &diagnostics);
+	fs::rename(&binary, destination)?;
 	Ok(())
 }
 
-fn print_tokens(lexer: &mut Lexer) {
-	loop {
-		let result = lexer.next_token();
-		if let Ok(token) = result {
-			log::info(&token.to_string());
-			if token.ttype == TokenType::EOF {
-				return;
-			}
-		} else {
-			log::error(&result.err().unwrap().to_string());
+/** Durations of the build phases, printed by `--timings`. */
+struct Timings {
+	enabled: bool,
+	start: Instant,
+	phases: Vec<(&'static str, String)>,
+}
+
+impl Timings {
+	fn new(enabled: bool) -> Timings {
+		Timings { enabled, start: Instant::now(), phases: vec![] }
+	}
+	fn measure<T>(&mut self, phase: &'static str, f: impl FnOnce() -> Result<T, DoitError>) -> Result<T, DoitError> {
+		let start = Instant::now();
+		let result = f();
+		self.phases.push((phase, format_duration(start.elapsed())));
+		result
+	}
+	fn skip(&mut self, phase: &'static str, reason: &str) {
+		self.phases.push((phase, reason.to_string()));
+	}
+	fn report(&self) {
+		if !self.enabled {
 			return;
 		}
+		let width = self.phases.iter().map(|(phase, _)| phase.len()).max().unwrap_or(0).max("total".len());
+		eprintln!("\x1b[32mTimings:\x1b[0m");
+		for (phase, value) in &self.phases {
+			eprintln!("  \x1b[34m{phase:<width$}\x1b[0m  {value}");
+		}
+		eprintln!("  \x1b[34m{:<width$}\x1b[0m  {}", "total", format_duration(self.start.elapsed()));
 	}
 }
-fn print_nodes(parser: &mut Parser) {
-	match parser.parse() {
-		Ok(root) => root.to_string().trim().split('\n').for_each(log::info),
-		Err(err) => log::error(&format!("Failed to parse: {}", err)),
-	};
+
+pub(crate) fn format_duration(duration: Duration) -> String {
+	format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
 }
 
-fn print_targets(parser: &mut Parser) {
-	match parser.parse() {
-		Ok(root) => {
-			let mut filtered: Vec<String> = root
-				.children
-				.into_iter()
-				.filter(|node| node.ntype == NodeType::TARGET)
-				.filter_map(|node| node.value.value)
-				.collect();
-			filtered.sort();
-			filtered.into_iter().for_each(|n| println!("{}", n));
+/** A single g++ diagnostic that was attributed to the do.it file through the generated `#line` directives. */
+#[derive(Debug, PartialEq)]
+struct CompileMessage<'a> {
+	line: usize,
+	severity: &'a str,
+	message: &'a str,
+}
+
+fn parse_compile_message<'a>(filename: &str, line: &'a str) -> Option<CompileMessage<'a>> {
+	let mut parts = line.strip_prefix(filename)?.strip_prefix(':')?.splitn(4, ':');
+	let lineno = parts.next()?.parse().ok()?;
+	// The column refers to the generated C++ and is of no use to the do.it author
+	parts.next()?.parse::<usize>().ok()?;
+	Some(CompileMessage { line: lineno, severity: parts.next()?.trim(), message: parts.next()?.trim() })
+}
+
+/** Converts the g++ output into diagnostics that point at the do.it line that produced each message. */
+fn map_compile_output(filename: &str, source: &str, stderr: &str) -> Vec<Diagnostic> {
+	let lines: Vec<&str> = source.lines().collect();
+	let mut diagnostics: Vec<Diagnostic> = vec![];
+	let mut target: Option<String> = None;
+	for line in stderr.lines() {
+		let Some(message) = parse_compile_message(filename, line) else {
+			if let Some(name) = line.split("In function 'void script::").nth(1) {
+				target = name.split('(').next().map(str::to_string);
+			} else if !line.starts_with(filename) {
+				eprintln!("{line}");
+			}
+			continue;
+		};
+		let (severity, code) = match message.severity {
+			"error" | "fatal error" => (Severity::Error, Some(codes::CPP_COMPILE)),
+			"warning" => (Severity::Warning, None),
+			_ => (Severity::Note, None),
+		};
+		let source_line = lines.get(message.line.wrapping_sub(1)).copied().unwrap_or_default();
+		// g++ quotes the offending symbol, which lets us underline it in the do.it source
+		let span = message
+			.message
+			.split('\'')
+			.nth(1)
+			.filter(|symbol| !symbol.is_empty())
+			.and_then(|symbol| {
+				let column = source_line[..source_line.find(symbol)?].chars().count() + 1;
+				Some(Span::new(message.line, column, message.line, column + symbol.chars().count() - 1))
+			})
+			.unwrap_or(Span::new(message.line, 1, message.line, source_line.chars().count().max(1)));
+		let mut diagnostic = Diagnostic::new(severity, code, message.message, span);
+		if let Some(name) = target.as_ref() {
+			diagnostic = diagnostic.with_note(&format!("in target '{name}'"));
 		}
-		Err(err) => log::error(&format!("Failed to parse: {}", err)),
-	};
+		diagnostics.push(diagnostic);
+	}
+	diagnostics
+}
+
+/** Prints the diagnostics carried by `error` against the do.it source before passing it on. */
+fn report(filename: &str, source: &str, error: DoitError) -> DoitError {
+	diagnostics::emit(filename, source, error.diagnostics());
+	error
+}
+
+fn print_tokens(lexer: &mut Lexer) -> Result<(), DoitError> {
+	loop {
+		let token = lexer.next_token()?;
+		log::info(&token.to_string());
+		if token.ttype == TokenType::EOF {
+			return Ok(());
+		}
+	}
+}
+fn print_nodes(parser: &mut Parser) -> Result<(), DoitError> {
+	parser.parse()?.to_string().trim().split('\n').for_each(log::info);
+	Ok(())
+}
+
+fn print_targets(parser: &mut Parser) -> Result<(), DoitError> {
+	let mut filtered: Vec<String> = parser
+		.parse()?
+		.children
+		.into_iter()
+		.filter(|node| node.ntype == NodeType::TARGET)
+		.filter_map(|node| node.value.value)
+		.collect();
+	filtered.sort();
+	filtered.into_iter().for_each(|n| println!("{}", n));
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{CompileMessage, parse_compile_message};
+
+	#[test]
+	fn test_parse_compile_message() {
+		assert_eq!(
+			parse_compile_message("/tmp/do.it", "/tmp/do.it:3:35: error: 'missing' was not declared in this scope"),
+			Some(CompileMessage { line: 3, severity: "error", message: "'missing' was not declared in this scope" })
+		);
+		assert_eq!(
+			parse_compile_message("/tmp/do.it", "/tmp/do.it:12:1: warning: unused variable 'x'"),
+			Some(CompileMessage { line: 12, severity: "warning", message: "unused variable 'x'" })
+		);
+		assert_eq!(parse_compile_message("/tmp/do.it", "/tmp/do.it: In function 'void script::a(int, const char**)':"), None);
+		assert_eq!(parse_compile_message("/tmp/do.it", "targets.cpp:205:1: error: expected ';'"), None);
+	}
 }
@@ -0,0 +1,170 @@
+pub const UNEXPECTED_EOF: &str = "E001";
+pub const MALFORMED_STRING: &str = "E002";
+pub const MALFORMED_BLOCK: &str = "E003";
+pub const UNEXPECTED_TOKEN: &str = "E004";
+pub const DUPLICATE_HELP: &str = "E005";
+pub const INVALID_EXPRESSION: &str = "E006";
+pub const INTERNAL: &str = "E007";
+pub const CPP_COMPILE: &str = "E008";
+pub const DUPLICATE_TARGET: &str = "E009";
+pub const MISPLACED_STATEMENT: &str = "E010";
+
+/** Long form descriptions printed by `doit --explain <code>`. */
+const EXPLANATIONS: &[(&str, &str)] = &[
+	(
+		UNEXPECTED_EOF,
+		r#"The do.it file ended in the middle of a statement.
+
+This usually means a string literal is missing its closing `"`, or a target
+name was written without the `=`, `{` or `:` that should follow it.
+
+    name = "unterminated
+    build"#,
+	),
+	(
+		MALFORMED_STRING,
+		r#"A string literal could not be read.
+
+String literals are delimited by double quotes and must be closed before the
+end of the file.
+Only `\\` and `\"` are valid escape sequences inside of them.
+
+    bad = "C:\path"      # error: `\p` is not a valid escape
+    good = "C:\\path""#,
+	),
+	(
+		MALFORMED_BLOCK,
+		r#"A `$$$`, `%%%`, `!!!`, `@@@` or `###` block could not be read.
+
+The opening delimiter of a block must be the only thing on its line, and the
+block must be closed by the same delimiter before the end of the file.
+
+    build {
+        $$$ echo "not allowed"
+        $$$
+    }
+
+A `!!!` block must name the program that runs it after its opening delimiter,
+or start with a `#!` line.
+
+    build {
+        !!! node
+        console.log("hello")
+        !!!
+    }
+
+The indentation that every line of a script block shares is removed, so
+it must be written the same way on each line, either with tabs or with spaces.
+Follow the opening delimiter with `verbatim` to keep the block as written.
+
+    build {
+        %%% verbatim
+            print("indented with spaces in a tab indented file")
+        %%%
+    }"#,
+	),
+	(
+		UNEXPECTED_TOKEN,
+		r#"The parser found a token where it is not allowed.
+
+Statements must start with a name (assignment or target), a script (`$`, `%`, `!!!`),
+a help block (`@`), an argument (`req`, `opt`), `exit`, `yield` or a comment.
+A name must be followed by `=`, `{` or `:`.
+
+    "stray string"
+    build ? {}"#,
+	),
+	(
+		DUPLICATE_HELP,
+		r#"A scope defines more than one help block.
+
+The global scope and every target may only have a single `@` or `@@@` help
+block. Merge the text into one block instead.
+
+    build {
+        @ Build the project
+        @ Also runs the tests   # error: second help block
+    }"#,
+	),
+	(
+		INVALID_EXPRESSION,
+		r#"An assignment or exit statement contains an invalid expression.
+
+Expressions are made of number literals, string literals, variable names and
+arithmetic operators, and an assignment must have at least one of them. They
+follow the rules of the generated C++: a variable keeps the type it was first
+assigned, and a string can only be added to a string, of which one side must be
+a variable.
+
+    empty =
+    name = 42
+    greeting = "Hello, " + "world"   # error: two string literals
+    count = 1 + name"#,
+	),
+	(
+		INTERNAL,
+		r#"doit reached a state that should not be possible.
+
+This is a bug in doit rather than in the do.it file. Please report it along
+with the do.it file that triggered it."#,
+	),
+	(
+		CPP_COMPILE,
+		r#"The C++ generated from the do.it file failed to compile.
+
+Expressions in assignments and exit statements are passed straight through to
+C++, so they must be valid C++ expressions. The most common cause is using a
+variable that was never assigned, or one that was assigned in another target.
+
+    build {
+        exit missing + 1    # error: 'missing' was not declared
+    }"#,
+	),
+	(
+		DUPLICATE_TARGET,
+		r#"A target name is defined more than once in the same scope.
+
+Every target becomes a function in the generated program, so each name may
+only be used once. Rename one of the targets or merge their bodies.
+
+    build {
+        $ make
+    }
+    build: $ make install    # error: 'build' is already defined"#,
+	),
+	(
+		MISPLACED_STATEMENT,
+		r#"A statement is used in a scope where it is not allowed.
+
+The global scope may only contain help, comments, targets and the first
+assignment of each global variable. Scripts, `exit` and `yield` only run as
+part of a target, and targets can not be nested inside of other targets.
+
+    $ echo "hello"          # error: script outside of a target
+    count = 1
+    count = 2               # error: globals may only be reassigned in a target
+    build {
+        count = 3           # ok
+    }"#,
+	),
+];
+
+pub fn explain(code: &str) -> Option<&'static str> {
+	EXPLANATIONS
+		.iter()
+		.find(|(id, _)| id.eq_ignore_ascii_case(code))
+		.map(|(_, text)| *text)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{EXPLANATIONS, explain};
+
+	#[test]
+	fn test_explain() {
+		assert!(explain("E001").is_some());
+		assert!(explain("e004").is_some());
+		assert!(explain("E999").is_none());
+		EXPLANATIONS.iter().for_each(|(code, text)| assert!(code.starts_with('E') && !text.is_empty()));
+	}
+}
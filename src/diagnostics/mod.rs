@@ -0,0 +1,251 @@
+use core::fmt;
+
+use crate::lexer::token::Span;
+
+pub mod codes;
+
+const TAB_WIDTH: usize = 4;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+	Error,
+	Warning,
+	Note,
+}
+
+impl Severity {
+	fn colour(&self) -> &'static str {
+		match self {
+			Severity::Error => "\x1b[1;31m",
+			Severity::Warning => "\x1b[1;33m",
+			Severity::Note => "\x1b[1;32m",
+		}
+	}
+}
+
+impl fmt::Display for Severity {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Severity::Error => "error",
+			Severity::Warning => "warning",
+			Severity::Note => "note",
+		})
+	}
+}
+
+/** A secondary span that gives context to the primary location of a diagnostic. */
+#[derive(Debug, Clone)]
+pub struct Label {
+	pub span: Span,
+	pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+	pub severity: Severity,
+	pub code: Option<&'static str>,
+	pub message: String,
+	pub span: Span,
+	/** Text printed beside the primary caret underline */
+	pub label: Option<String>,
+	pub labels: Vec<Label>,
+	pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+	pub fn new(severity: Severity, code: Option<&'static str>, message: &str, span: Span) -> Diagnostic {
+		Diagnostic {
+			severity,
+			code,
+			message: message.to_string(),
+			span,
+			label: None,
+			labels: vec![],
+			notes: vec![],
+		}
+	}
+	pub fn error(code: &'static str, message: &str, span: Span) -> Diagnostic {
+		Diagnostic::new(Severity::Error, Some(code), message, span)
+	}
+	pub fn with_label(mut self, message: &str) -> Diagnostic {
+		self.label = Some(message.to_string());
+		self
+	}
+	pub fn with_secondary(mut self, span: Span, message: &str) -> Diagnostic {
+		self.labels.push(Label { span, message: message.to_string() });
+		self
+	}
+	pub fn with_note(mut self, note: &str) -> Diagnostic {
+		self.notes.push(note.to_string());
+		self
+	}
+
+	/** Renders the diagnostic with the offending source lines and caret underlines. */
+	pub fn render(&self, filename: &str, source: &str) -> String {
+		let lines: Vec<&str> = source.lines().collect();
+		let code = self.code.map(|c| format!("[{c}]")).unwrap_or_default();
+		let mut result = format!("{}{}{}\x1b[0m\x1b[1m: {}\x1b[0m\n", self.severity.colour(), self.severity, code, self.message);
+
+		// Every line that is referenced by one of the spans, with the underlines to draw beneath it
+		let mut underlines: Vec<(usize, &Span, bool, Option<&str>)> = vec![];
+		if !self.span.is_empty() {
+			underlines.push((self.span.start_line, &self.span, true, self.label.as_deref()));
+		}
+		for label in self.labels.iter().filter(|label| !label.span.is_empty()) {
+			underlines.push((label.span.start_line, &label.span, false, Some(&label.message)));
+		}
+		underlines.sort_by_key(|(line, _, primary, _)| (*line, !*primary));
+		let width = underlines.iter().map(|(line, ..)| line.to_string().len()).max().unwrap_or(1);
+		let gutter = format!("\x1b[1;34m{} |\x1b[0m", " ".repeat(width));
+
+		if self.span.is_empty() {
+			result.push_str(&format!("\x1b[1;34m{}-->\x1b[0m {}\n", " ".repeat(width), filename));
+		} else {
+			result.push_str(&format!(
+				"\x1b[1;34m{}-->\x1b[0m {}:{}:{}\n",
+				" ".repeat(width),
+				filename,
+				self.span.start_line,
+				self.span.start_col
+			));
+		}
+		if !underlines.is_empty() {
+			result.push_str(&format!("{gutter}\n"));
+		}
+		let mut previous = 0;
+		for (lineno, span, primary, message) in underlines {
+			// Spans at the end of the file point one past the last line
+			let Some(text) = lines.get(lineno - 1).or(if lineno == lines.len() + 1 { Some(&"") } else { None }) else {
+				continue;
+			};
+			if lineno != previous {
+				result.push_str(&format!(
+					"\x1b[1;34m{:>width$} |\x1b[0m {}\n",
+					lineno,
+					expand_tabs(text),
+					width = width
+				));
+				previous = lineno;
+			}
+			let start = visual_column(text, span.start_col);
+			let end = if span.end_line == span.start_line {
+				visual_column(text, span.end_col + 1)
+			} else {
+				visual_column(text, text.chars().count() + 1)
+			};
+			let (marker, colour) = if primary { ('^', self.severity.colour()) } else { ('-', "\x1b[1;34m") };
+			result.push_str(&format!(
+				"{} {}{}{}{}\x1b[0m\n",
+				gutter,
+				" ".repeat(start),
+				colour,
+				marker.to_string().repeat(end.saturating_sub(start).max(1)),
+				message.map(|m| format!(" {m}")).unwrap_or_default(),
+			));
+		}
+		if !self.notes.is_empty() {
+			result.push_str(&format!("{gutter}\n"));
+		}
+		for note in &self.notes {
+			result.push_str(&format!("{} \x1b[1m= note:\x1b[0m {}\n", " ".repeat(width), note));
+		}
+		result
+	}
+}
+
+/** Prints every diagnostic to stderr, followed by a hint on how to get a longer explanation of their codes. */
+pub fn emit(filename: &str, source: &str, diagnostics: &[Diagnostic]) {
+	let mut explained: Vec<&str> = vec![];
+	for diagnostic in diagnostics {
+		eprintln!("{}", diagnostic.render(filename, source));
+		if let Some(code) = diagnostic.code
+			&& !explained.contains(&code)
+			&& codes::explain(code).is_some()
+		{
+			explained.push(code);
+		}
+	}
+	match explained.as_slice() {
+		[] => {}
+		[code] => eprintln!("\x1b[1mFor more information about this error, try `doit --explain {code}`.\x1b[0m"),
+		codes => eprintln!(
+			"\x1b[1mSome errors have detailed explanations: {}.\x1b[0m\n\x1b[1mFor more information about an error, try `doit --explain {}`.\x1b[0m",
+			codes.join(", "),
+			codes[0]
+		),
+	}
+}
+
+impl fmt::Display for Diagnostic {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.code {
+			Some(code) => write!(f, "{}[{}] {}:{} > {}", self.severity, code, self.span.start_line, self.span.start_col, self.message),
+			None => write!(f, "{} {}:{} > {}", self.severity, self.span.start_line, self.span.start_col, self.message),
+		}
+	}
+}
+
+fn expand_tabs(text: &str) -> String {
+	text.replace('\t', &" ".repeat(TAB_WIDTH))
+}
+
+/** Converts a 1-based character column into a 0-based terminal column with tabs expanded. */
+fn visual_column(text: &str, column: usize) -> usize {
+	text.chars()
+		.take(column.saturating_sub(1))
+		.map(|c| if c == '\t' { TAB_WIDTH } else { 1 })
+		.sum::<usize>()
+		+ column.saturating_sub(1).saturating_sub(text.chars().count())
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::lexer::token::Span;
+
+	use super::{Diagnostic, visual_column};
+
+	fn strip_colours(text: &str) -> String {
+		let mut result = String::new();
+		let mut escaped = false;
+		for c in text.chars() {
+			if c == '\x1b' {
+				escaped = true;
+			} else if escaped {
+				escaped = c != 'm';
+			} else {
+				result.push(c);
+			}
+		}
+		result
+	}
+
+	#[test]
+	fn test_visual_column() {
+		assert_eq!(visual_column("\tabc", 1), 0);
+		assert_eq!(visual_column("\tabc", 2), 4);
+		assert_eq!(visual_column("\tabc", 4), 6);
+		assert_eq!(visual_column("abc", 5), 4);
+	}
+
+	#[test]
+	fn test_render() {
+		let source = "tgt {\n\t$ echo\n\t@ one\n\t@ two\n}\n";
+		let diagnostic = Diagnostic::error("E005", "Help block has already been defined", Span::new(4, 2, 4, 6))
+			.with_label("second help block")
+			.with_secondary(Span::new(3, 2, 3, 6), "first defined here")
+			.with_note("only one help block is allowed per scope");
+		assert_eq!(
+			strip_colours(&diagnostic.render("do.it", source)),
+			r"error[E005]: Help block has already been defined
+ --> do.it:4:2
+  |
+3 |     @ one
+  |     ----- first defined here
+4 |     @ two
+  |     ^^^^^ second help block
+  |
+  = note: only one help block is allowed per scope
+"
+		);
+	}
+}
@@ -0,0 +1,124 @@
+use core::fmt;
+use std::io;
+
+use crate::diagnostics::Diagnostic;
+
+/**
+Every way a doit invocation can fail. Each variant maps to its own process exit code (see [`DoitError::exit_code`])
+so that scripts and CI can tell a broken do.it file apart from a missing compiler or a runner that failed to start.
+*/
+#[derive(Debug)]
+pub enum DoitError {
+	/** Reading or writing files (do.it source, cache directory) failed */
+	Io(io::Error),
+	/** The do.it file contains characters or blocks that can not be tokenized */
+	Lex(Vec<Diagnostic>),
+	/** The do.it file is made of valid tokens, but they are not in a valid order */
+	Parse(Vec<Diagnostic>),
+	/** The do.it file parses, but its meaning is invalid (ie. duplicate targets) */
+	Semantic(Vec<Diagnostic>),
+	/** The C++ could not be generated, or the generated C++ failed to compile */
+	Codegen(Vec<Diagnostic>),
+	/** The C++ compiler is missing or unusable */
+	Toolchain(String),
+	/** The compiled targets binary could not be started or waited on */
+	Runtime(String),
+}
+
+impl DoitError {
+	pub const EXIT_LEX: u8 = 65;
+	pub const EXIT_PARSE: u8 = 66;
+	pub const EXIT_SEMANTIC: u8 = 67;
+	pub const EXIT_CODEGEN: u8 = 68;
+	pub const EXIT_TOOLCHAIN: u8 = 69;
+	pub const EXIT_RUNTIME: u8 = 70;
+	pub const EXIT_IO: u8 = 74;
+
+	pub fn exit_code(&self) -> u8 {
+		match self {
+			DoitError::Io(_) => DoitError::EXIT_IO,
+			DoitError::Lex(_) => DoitError::EXIT_LEX,
+			DoitError::Parse(_) => DoitError::EXIT_PARSE,
+			DoitError::Semantic(_) => DoitError::EXIT_SEMANTIC,
+			DoitError::Codegen(_) => DoitError::EXIT_CODEGEN,
+			DoitError::Toolchain(_) => DoitError::EXIT_TOOLCHAIN,
+			DoitError::Runtime(_) => DoitError::EXIT_RUNTIME,
+		}
+	}
+
+	/** The diagnostics carried by the error, which is empty for failures that have no do.it location. */
+	pub fn diagnostics(&self) -> &[Diagnostic] {
+		match self {
+			DoitError::Lex(diagnostics)
+			| DoitError::Parse(diagnostics)
+			| DoitError::Semantic(diagnostics)
+			| DoitError::Codegen(diagnostics) => diagnostics,
+			_ => &[],
+		}
+	}
+
+	/** One line description of the failure, for use after the diagnostics themselves have been rendered. */
+	pub fn summary(&self) -> String {
+		match self.diagnostics().len() {
+			0 => self.to_string(),
+			1 => "Aborting due to previous error".to_string(),
+			count => format!("Aborting due to {count} previous errors"),
+		}
+	}
+}
+
+impl fmt::Display for DoitError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			DoitError::Io(err) => write!(f, "{err}"),
+			DoitError::Toolchain(message) | DoitError::Runtime(message) => f.write_str(message),
+			_ => {
+				let lines: Vec<String> = self.diagnostics().iter().map(|diagnostic| diagnostic.to_string()).collect();
+				f.write_str(&lines.join("\n"))
+			}
+		}
+	}
+}
+
+impl std::error::Error for DoitError {}
+
+impl From<io::Error> for DoitError {
+	fn from(err: io::Error) -> Self {
+		DoitError::Io(err)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io;
+
+	use crate::{diagnostics::Diagnostic, lexer::token::Span};
+
+	use super::DoitError;
+
+	#[test]
+	fn test_exit_codes_are_distinct() {
+		let diagnostic = || vec![Diagnostic::error("E004", "unexpected", Span::new(1, 1, 1, 1))];
+		let errors = [
+			DoitError::Io(io::Error::other("io")),
+			DoitError::Lex(diagnostic()),
+			DoitError::Parse(diagnostic()),
+			DoitError::Semantic(diagnostic()),
+			DoitError::Codegen(diagnostic()),
+			DoitError::Toolchain("g++".to_string()),
+			DoitError::Runtime("spawn".to_string()),
+		];
+		let mut codes: Vec<u8> = errors.iter().map(DoitError::exit_code).collect();
+		codes.sort();
+		codes.dedup();
+		assert_eq!(codes.len(), errors.len());
+	}
+
+	#[test]
+	fn test_summary() {
+		let diagnostic = Diagnostic::error("E004", "unexpected", Span::new(1, 1, 1, 1));
+		assert_eq!(DoitError::Parse(vec![diagnostic.clone()]).summary(), "Aborting due to previous error");
+		assert_eq!(DoitError::Parse(vec![diagnostic.clone(), diagnostic]).summary(), "Aborting due to 2 previous errors");
+		assert_eq!(DoitError::Toolchain("g++ not found".to_string()).summary(), "g++ not found");
+	}
+}
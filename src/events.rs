@@ -0,0 +1,493 @@
+use std::{
+	fs::{self, OpenOptions},
+	io::{self, Write},
+	path::Path,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::compiler::format_duration;
+
+/**
+What happened during a run. The targets binary and the interpreter append these as JSON lines to the file named by
+`DOIT_EVENTS`, beside the compile events that doit itself records, and the reports are built from them once it exits.
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EventKind {
+	CompileStart,
+	CompileEnd,
+	TargetStart,
+	TargetEnd,
+	ScriptStart,
+	ScriptEnd,
+}
+
+impl EventKind {
+	const NAMES: [(EventKind, &'static str); 6] = [
+		(EventKind::CompileStart, "compile_start"),
+		(EventKind::CompileEnd, "compile_end"),
+		(EventKind::TargetStart, "target_start"),
+		(EventKind::TargetEnd, "target_end"),
+		(EventKind::ScriptStart, "script_start"),
+		(EventKind::ScriptEnd, "script_end"),
+	];
+
+	pub fn as_str(&self) -> &'static str {
+		EventKind::NAMES.iter().find(|(kind, _)| kind == self).map(|(_, name)| *name).unwrap_or_default()
+	}
+
+	fn from_name(name: &str) -> Option<EventKind> {
+		EventKind::NAMES.iter().find(|(_, other)| *other == name).map(|(kind, _)| *kind)
+	}
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Event {
+	pub kind: EventKind,
+	/** Microseconds since the unix epoch */
+	pub time: u64,
+	/** The target name, or the injected command of a script */
+	pub name: String,
+	/** The line of the script in the do.it file */
+	pub line: usize,
+	/** The exit code of the script or target */
+	pub status: i32,
+	/** The CLI arguments that a target was run with */
+	pub args: Vec<String>,
+	/** The stdout of a failed script, when the run captures it */
+	pub output: String,
+	/** The stderr of a failed script, when the run captures it */
+	pub error: String,
+}
+
+impl Event {
+	pub fn now(kind: EventKind, name: &str, line: usize, status: i32) -> Event {
+		let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+		Event {
+			kind,
+			time,
+			name: name.to_string(),
+			line,
+			status,
+			args: vec![],
+			output: String::new(),
+			error: String::new(),
+		}
+	}
+
+	/** The same line that `::doit::event` writes. */
+	pub fn to_json(&self) -> String {
+		format!(
+			"{{\"event\":\"{}\",\"time\":{},\"name\":{},\"line\":{},\"status\":{},\"args\":{},\"output\":{},\"error\":{}}}",
+			self.kind.as_str(),
+			self.time,
+			json_string(&self.name),
+			self.line,
+			self.status,
+			json_list(&self.args),
+			json_string(&self.output),
+			json_string(&self.error)
+		)
+	}
+
+	pub fn parse(line: &str) -> Option<Event> {
+		let fields = parse_object(line)?;
+		let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, value)| value);
+		let scalar = |name: &str| match field(name)? {
+			Field::Scalar(value) => Some(value.as_str()),
+			Field::List(_) => None,
+		};
+		Some(Event {
+			kind: EventKind::from_name(scalar("event")?)?,
+			time: scalar("time")?.parse().ok()?,
+			name: scalar("name")?.to_string(),
+			line: scalar("line")?.parse().ok()?,
+			status: scalar("status")?.parse().ok()?,
+			args: match field("args")? {
+				Field::List(args) => args.clone(),
+				Field::Scalar(_) => return None,
+			},
+			output: scalar("output")?.to_string(),
+			error: scalar("error")?.to_string(),
+		})
+	}
+
+	pub fn elapsed(&self, start: &Event) -> Duration {
+		Duration::from_micros(self.time.saturating_sub(start.time))
+	}
+}
+
+pub fn record(path: &Path, event: &Event) -> io::Result<()> {
+	let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+	writeln!(file, "{}", event.to_json())
+}
+
+/** Reads the events of a run, skipping any line that a crashing process left incomplete. */
+pub fn load(path: &Path) -> Vec<Event> {
+	fs::read_to_string(path).unwrap_or_default().lines().filter_map(Event::parse).collect()
+}
+
+pub fn json_string(text: &str) -> String {
+	let mut result = String::from("\"");
+	for c in text.chars() {
+		match c {
+			'"' => result.push_str("\\\""),
+			'\\' => result.push_str("\\\\"),
+			'\n' => result.push_str("\\n"),
+			'\r' => result.push_str("\\r"),
+			'\t' => result.push_str("\\t"),
+			c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+			c => result.push(c),
+		}
+	}
+	result.push('"');
+	result
+}
+
+pub fn json_list(items: &[String]) -> String {
+	format!("[{}]", items.iter().map(|item| json_string(item)).collect::<Vec<String>>().join(","))
+}
+
+/** A value of an event line: a string or number, or a list of strings. */
+enum Field {
+	Scalar(String),
+	List(Vec<String>),
+}
+
+/** Parses a flat JSON object of strings, numbers and lists of strings, which is all that an event line contains. */
+fn parse_object(line: &str) -> Option<Vec<(String, Field)>> {
+	let mut chars = line.trim().chars().peekable();
+	let mut fields: Vec<(String, Field)> = vec![];
+	if chars.next()? != '{' {
+		return None;
+	}
+	loop {
+		match chars.next()? {
+			'}' => return Some(fields),
+			',' => continue,
+			'"' => {}
+			_ => return None,
+		}
+		let key = parse_string(&mut chars)?;
+		if chars.next()? != ':' {
+			return None;
+		}
+		let value = match chars.peek()? {
+			'"' => {
+				chars.next();
+				Field::Scalar(parse_string(&mut chars)?)
+			}
+			'[' => {
+				chars.next();
+				let mut items: Vec<String> = vec![];
+				loop {
+					match chars.next()? {
+						']' => break,
+						',' => continue,
+						'"' => items.push(parse_string(&mut chars)?),
+						_ => return None,
+					}
+				}
+				Field::List(items)
+			}
+			_ => {
+				let mut number = String::new();
+				while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '-') {
+					number.push(c);
+				}
+				Field::Scalar(number)
+			}
+		};
+		fields.push((key, value));
+	}
+}
+
+/** Parses the rest of a JSON string, after its opening quote. */
+fn parse_string(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+	let mut result = String::new();
+	loop {
+		match chars.next()? {
+			'"' => return Some(result),
+			'\\' => match chars.next()? {
+				'n' => result.push('\n'),
+				'r' => result.push('\r'),
+				't' => result.push('\t'),
+				'u' => {
+					let code: String = chars.take(4).collect();
+					result.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+				}
+				c => result.push(c),
+			},
+			c => result.push(c),
+		}
+	}
+}
+
+/** The `--time` table: the compile, then each target and the scripts it ran, with their wall clock durations. */
+pub fn render_times(events: &[Event]) -> String {
+	let mut rows: Vec<(String, Option<Duration>)> = vec![];
+	let mut open: Vec<(usize, &Event)> = vec![];
+	for event in events {
+		let label = match event.kind {
+			EventKind::CompileStart => "compile".to_string(),
+			EventKind::TargetStart => event.name.clone(),
+			EventKind::ScriptStart => {
+				format!("  line {}: {}", event.line, event.name.lines().next().unwrap_or_default())
+			}
+			_ => {
+				if let Some((index, start)) = open.pop() {
+					rows[index].1 = Some(event.elapsed(start));
+				}
+				continue;
+			}
+		};
+		open.push((rows.len(), event));
+		rows.push((truncate(&label, 60), None));
+	}
+	let width = rows.iter().map(|(label, _)| label.chars().count()).max().unwrap_or(0);
+	let mut out = String::from("\x1b[32mTimes:\x1b[0m\n");
+	for (label, duration) in rows {
+		// A target that was killed never records its end
+		let duration = duration.map(format_duration).unwrap_or("-".to_string());
+		out.push_str(&format!("  \x1b[34m{label:<width$}\x1b[0m  {duration}\n"));
+	}
+	out
+}
+
+/**
+The events in the [Trace Event Format](https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU)
+that `chrome://tracing` and Perfetto load, with the compile, each target and each script as a slice.
+*/
+pub fn render_chrome_trace(events: &[Event]) -> String {
+	let slices: Vec<String> = events
+		.iter()
+		.map(|event| {
+			let (phase, category, name, args) = match event.kind {
+				EventKind::CompileStart => ("B", "compile", "compile", String::from("{}")),
+				EventKind::TargetStart => {
+					("B", "target", event.name.as_str(), format!("{{\"args\":{}}}", json_list(&event.args)))
+				}
+				EventKind::ScriptStart => (
+					"B",
+					"script",
+					event.name.lines().next().unwrap_or_default(),
+					format!("{{\"command\":{},\"line\":{}}}", json_string(&event.name), event.line),
+				),
+				EventKind::CompileEnd | EventKind::TargetEnd | EventKind::ScriptEnd => {
+					("E", "", "", format!("{{\"status\":{}}}", event.status))
+				}
+			};
+			format!(
+				"{{\"name\":{},\"cat\":\"{category}\",\"ph\":\"{phase}\",\"ts\":{},\"pid\":1,\"tid\":1,\"args\":{args}}}",
+				json_string(name),
+				event.time
+			)
+		})
+		.collect();
+	format!("{{\"traceEvents\":[\n{}\n],\"displayTimeUnit\":\"ms\"}}\n", slices.join(",\n"))
+}
+
+/** The start and end of a target or script, where the end is missing if it was killed. */
+type Interval<'a> = (&'a Event, Option<&'a Event>);
+
+/**
+The `--junit` report, with a testsuite for each target that ran and a testcase for each of its scripts. A script
+that failed, or never finished, keeps its exit code in the failure and its captured stdout and stderr in the
+`system-out` and `system-err` of its testcase.
+*/
+pub fn render_junit(filename: &str, events: &[Event]) -> String {
+	let mut suites: Vec<(Interval, Vec<Interval>)> = vec![];
+	for event in events {
+		match (event.kind, suites.last_mut()) {
+			(EventKind::TargetStart, _) => suites.push(((event, None), vec![])),
+			(EventKind::TargetEnd, Some(suite)) => suite.0.1 = Some(event),
+			(EventKind::ScriptStart, Some(suite)) => suite.1.push((event, None)),
+			(EventKind::ScriptEnd, Some(suite)) => {
+				if let Some(case) = suite.1.last_mut().filter(|case| case.1.is_none()) {
+					case.1 = Some(event);
+				}
+			}
+			_ => {}
+		}
+	}
+	let seconds = |start: &Event, end: Option<&Event>| end.map(|end| end.elapsed(start).as_secs_f64()).unwrap_or(0.0);
+	let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+	for ((start, end), cases) in suites {
+		let failures = cases.iter().filter(|(_, end)| end.is_none_or(|end| end.status != 0)).count();
+		out.push_str(&format!(
+			"  <testsuite name={} tests=\"{}\" failures=\"{failures}\" time=\"{:.3}\" file={}>\n",
+			xml_attribute(&start.name),
+			cases.len(),
+			seconds(start, end),
+			xml_attribute(filename)
+		));
+		for (case, case_end) in cases {
+			let name = format!("line {}: {}", case.line, case.name.lines().next().unwrap_or_default());
+			out.push_str(&format!(
+				"    <testcase name={} classname={} time=\"{:.3}\" line=\"{}\"",
+				xml_attribute(&truncate(&name, 60)),
+				xml_attribute(&start.name),
+				seconds(case, case_end),
+				case.line
+			));
+			match case_end {
+				Some(case_end) if case_end.status == 0 => out.push_str("/>\n"),
+				Some(case_end) => {
+					out.push_str(&format!(
+						">\n      <failure message=\"exited with {}\" type=\"exit\"/>\n",
+						case_end.status
+					));
+					for (element, text) in [("system-out", &case_end.output), ("system-err", &case_end.error)] {
+						if !text.is_empty() {
+							out.push_str(&format!("      <{element}>{}</{element}>\n", xml_text(text)));
+						}
+					}
+					out.push_str("    </testcase>\n");
+				}
+				None => out.push_str(">\n      <failure message=\"did not finish\" type=\"killed\"/>\n    </testcase>\n"),
+			}
+		}
+		out.push_str("  </testsuite>\n");
+	}
+	out.push_str("</testsuites>\n");
+	out
+}
+
+fn xml_text(text: &str) -> String {
+	let mut result = String::new();
+	for c in text.chars() {
+		match c {
+			'&' => result.push_str("&amp;"),
+			'<' => result.push_str("&lt;"),
+			'>' => result.push_str("&gt;"),
+			'"' => result.push_str("&quot;"),
+			// Control characters other than whitespace are not allowed in XML 1.0, such as the escapes of colours
+			c if (c as u32) < 0x20 && !matches!(c, '\n' | '\r' | '\t') => {}
+			c => result.push(c),
+		}
+	}
+	result
+}
+
+fn xml_attribute(text: &str) -> String {
+	format!("\"{}\"", xml_text(text).replace('\n', "&#10;"))
+}
+
+/** The events as they were recorded, one JSON object per line. */
+pub fn render_json_lines(events: &[Event]) -> String {
+	events.iter().map(|event| event.to_json() + "\n").collect()
+}
+
+fn truncate(text: &str, width: usize) -> String {
+	match text.char_indices().nth(width) {
+		Some((index, _)) => format!("{}…", &text[..index]),
+		None => text.to_string(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Event, EventKind, render_chrome_trace, render_junit, render_times};
+
+	fn event(kind: EventKind, time: u64, name: &str, line: usize) -> Event {
+		Event {
+			kind,
+			time,
+			name: name.to_string(),
+			line,
+			status: 0,
+			args: vec![],
+			output: String::new(),
+			error: String::new(),
+		}
+	}
+
+	#[test]
+	fn test_event_json() {
+		let mut event = event(EventKind::ScriptEnd, 12, "echo \"a\\b\"\n\u{1}", 3);
+		event.status = -1;
+		event.args = vec!["a b".to_string(), "".to_string()];
+		event.error = "missing\n".to_string();
+		assert_eq!(
+			event.to_json(),
+			"{\"event\":\"script_end\",\"time\":12,\"name\":\"echo \\\"a\\\\b\\\"\\n\\u0001\",\"line\":3,\"status\":-1,\
+			\"args\":[\"a b\",\"\"],\"output\":\"\",\"error\":\"missing\\n\"}"
+		);
+		assert_eq!(Event::parse(&event.to_json()), Some(event));
+		assert_eq!(Event::parse("{\"event\":\"script_end\",\"time\":12"), None);
+		let unknown = "{\"event\":\"unknown\",\"time\":1,\"name\":\"\",\"line\":0,\"status\":0,\"args\":[],\"output\":\"\",\
+			\"error\":\"\"}";
+		assert_eq!(Event::parse(unknown), None);
+	}
+
+	#[test]
+	fn test_render_times() {
+		let events = [
+			event(EventKind::CompileStart, 0, "", 0),
+			event(EventKind::CompileEnd, 1500, "", 0),
+			event(EventKind::TargetStart, 2000, "build", 0),
+			event(EventKind::ScriptStart, 2000, "cargo build\n--release", 4),
+			event(EventKind::ScriptEnd, 5000, "", 4),
+			event(EventKind::TargetEnd, 6000, "build", 0),
+		];
+		assert_eq!(
+			render_times(&events),
+			"\x1b[32mTimes:\x1b[0m\n\
+			\x20 \x1b[34mcompile              \x1b[0m  1.5ms\n\
+			\x20 \x1b[34mbuild                \x1b[0m  4.0ms\n\
+			\x20 \x1b[34m  line 4: cargo build\x1b[0m  3.0ms\n"
+		);
+		assert!(render_times(&events[2..4]).ends_with("  -\n"));
+	}
+
+	#[test]
+	fn test_render_chrome_trace() {
+		let mut start = event(EventKind::TargetStart, 10, "build", 0);
+		start.args = vec!["release".to_string()];
+		let events = [start, event(EventKind::ScriptStart, 20, "ls\n-la", 4), event(EventKind::ScriptEnd, 30, "", 4)];
+		assert_eq!(
+			render_chrome_trace(&events),
+			"{\"traceEvents\":[\n\
+			{\"name\":\"build\",\"cat\":\"target\",\"ph\":\"B\",\"ts\":10,\"pid\":1,\"tid\":1,\"args\":{\"args\":[\"release\"]}},\n\
+			{\"name\":\"ls\",\"cat\":\"script\",\"ph\":\"B\",\"ts\":20,\"pid\":1,\"tid\":1,\"args\":{\"command\":\"ls\\n-la\",\"line\":4}},\n\
+			{\"name\":\"\",\"cat\":\"\",\"ph\":\"E\",\"ts\":30,\"pid\":1,\"tid\":1,\"args\":{\"status\":0}}\n\
+			],\"displayTimeUnit\":\"ms\"}\n"
+		);
+	}
+	#[test]
+	fn test_render_junit() {
+		let mut failed = event(EventKind::ScriptEnd, 3_500_000, "", 5);
+		failed.status = 2;
+		failed.output = "making install\n".to_string();
+		failed.error = "error: <missing> & \x1b[31mred\x1b[0m\n".to_string();
+		let events = [
+			event(EventKind::CompileStart, 0, "", 0),
+			event(EventKind::CompileEnd, 10, "", 0),
+			event(EventKind::TargetStart, 1_000_000, "build", 0),
+			event(EventKind::ScriptStart, 1_000_000, "make \"all\"\n-j", 4),
+			event(EventKind::ScriptEnd, 2_000_000, "", 4),
+			event(EventKind::ScriptStart, 2_000_000, "make install", 5),
+			failed,
+			event(EventKind::TargetEnd, 3_500_000, "build", 0),
+			event(EventKind::TargetStart, 4_000_000, "test", 0),
+			event(EventKind::ScriptStart, 4_000_000, "cargo test", 2),
+		];
+		assert_eq!(
+			render_junit("do.it", &events),
+			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n\
+			\x20 <testsuite name=\"build\" tests=\"2\" failures=\"1\" time=\"2.500\" file=\"do.it\">\n\
+			\x20   <testcase name=\"line 4: make &quot;all&quot;\" classname=\"build\" time=\"1.000\" line=\"4\"/>\n\
+			\x20   <testcase name=\"line 5: make install\" classname=\"build\" time=\"1.500\" line=\"5\">\n\
+			\x20     <failure message=\"exited with 2\" type=\"exit\"/>\n\
+			\x20     <system-out>making install\n</system-out>\n\
+			\x20     <system-err>error: &lt;missing&gt; &amp; [31mred[0m\n</system-err>\n\
+			\x20   </testcase>\n\
+			\x20 </testsuite>\n\
+			\x20 <testsuite name=\"test\" tests=\"1\" failures=\"1\" time=\"0.000\" file=\"do.it\">\n\
+			\x20   <testcase name=\"line 2: cargo test\" classname=\"test\" time=\"0.000\" line=\"2\">\n\
+			\x20     <failure message=\"did not finish\" type=\"killed\"/>\n\
+			\x20   </testcase>\n\
+			\x20 </testsuite>\n\
+			</testsuites>\n"
+		);
+	}
+}
@@ -1,197 +1,175 @@
-use std::io::{Error, ErrorKind};
-
 use crate::{
-	lexer::token::TokenType,
-	parser::nodes::{Node, NodeType},
+	error::DoitError,
+	lexer::token::{Span, TokenType},
+	program::{Expression, ScriptKind},
 };
 
-pub fn node_value(node: &Node) -> &str {
-	let value = node.value.value.as_ref();
-	match value {
-		Some(res) => AsRef::as_ref(res),
-		None => "",
+/** Emits a `#line` directive so that g++ reports errors against the do.it source rather than the generated file. */
+pub fn generate_line(span: &Span, filename: &str) -> String {
+	if span.is_empty() {
+		return String::new();
 	}
+	format!("#line {} \"{}\"\n", span.start_line, escape_filename(filename))
+}
+pub fn escape_filename(filename: &str) -> String {
+	filename.replace('\\', "\\\\").replace('"', "\\\"")
 }
 
-pub fn generate_variable(node: &Node, exists: bool) -> Result<String, Error> {
-	if node.value.value.is_none() {
-		return Err(Error::new(ErrorKind::InvalidData, ""));
-	}
-	let mut expression = String::new();
-	for child in &node.children {
-		if child.ntype != NodeType::SYMBOL {
-			return Err(Error::new(ErrorKind::InvalidData, "Unexpected node type"));
-		}
-		if let Some(value) = &child.value.value {
-			expression.push(' ');
-			if child.value.ttype == TokenType::LIT_STR {
-				expression.push('"');
-				expression.push_str(value);
-				expression.push('"');
+pub fn generate_variable(name: &str, expression: &Expression, exists: bool) -> Result<String, DoitError> {
+	let mut result = String::new();
+	for token in &expression.tokens {
+		if let Some(value) = &token.value {
+			result.push(' ');
+			if token.ttype == TokenType::LIT_STR {
+				result.push('"');
+				result.push_str(value);
+				result.push('"');
 			} else {
-				expression.push_str(value);
+				result.push_str(value);
 			}
 		}
 	}
 	let var_type = if exists {
 		""
-	} else if node.children[0].value.ttype == TokenType::LIT_NUM {
+	} else if expression.tokens.first().is_some_and(|token| token.ttype == TokenType::LIT_NUM) {
 		"double "
 	} else {
 		"::std::string "
 	};
-	Ok(format!(
-		"{}{} ={};\n",
-		var_type,
-		node.value.value.as_ref().unwrap(),
-		&expression
-	))
-}
-pub fn generate_script_sh(node: &Node, vars: &[&str]) -> Result<String, Error> {
-	let vars: Vec<String> = vars.iter().map(|var| format!("__VAR({})", *var)).collect();
-	Ok(format!(
-		r#"__SYSTEM_SH(R"__DOIT__({})__DOIT__", ::doit::args_map({{{}}}));{}"#,
-		node_value(node),
-		vars.join(","),
-		'\n'
-	))
+	Ok(format!("{}{} ={};\n", var_type, name, &result))
 }
-pub fn generate_script_py(node: &Node, vars: &[&str]) -> Result<String, Error> {
+pub fn generate_script(kind: &ScriptKind, script: &str, vars: &[&str], line: usize) -> Result<String, DoitError> {
 	let vars: Vec<String> = vars.iter().map(|var| format!("__VAR({})", *var)).collect();
+	let program = match kind {
+		ScriptKind::Program(program) => format!(r#"R"__DOIT__({})__DOIT__""#, program),
+		_ => String::from("\"\""),
+	};
 	Ok(format!(
-		r#"__SYSTEM_PY(R"__DOIT__({})__DOIT__", ::doit::args_map({{{}}}));{}"#,
-		node_value(node),
+		r#"__SCRIPT('{}', {}, R"__DOIT__({})__DOIT__", ::doit::args_map({{{}}}), {});{}"#,
+		kind.symbol(),
+		program,
+		script,
 		vars.join(","),
+		line,
 		'\n'
 	))
 }
-pub fn generate_comment(node: &Node) -> Result<String, Error> {
-	Ok(format!("//{}\n", node.value.value.as_ref().unwrap_or(&String::new())))
+pub fn generate_comment(comment: &str) -> Result<String, DoitError> {
+	Ok(format!("//{}\n", comment))
 }
-pub fn generate_exit(node: &Node) -> Result<String, Error> {
-	let expression: Vec<&str> = node.children.iter().map(node_value).collect();
+pub fn generate_exit(expression: Option<&Expression>) -> Result<String, DoitError> {
+	let expression: Vec<&str> = expression
+		.map(|expression| expression.tokens.iter().filter_map(|token| token.value.as_deref()).collect())
+		.unwrap_or_default();
 	Ok(format!("::doit::exit({});\n", expression.join(" ")))
 }
-pub fn generate_yield() -> Result<String, Error> {
+pub fn generate_yield() -> Result<String, DoitError> {
 	Ok(String::from("::doit::yield();\n"))
 }
 
 #[cfg(test)]
 mod tests {
-	use std::io::Error;
-
 	use crate::{
-		generator::generators::{generate_comment, generate_script_sh, generate_script_py, generate_variable},
-		lexer::token::{Token, TokenType},
-		parser::nodes::{Node, NodeType},
+		error::DoitError,
+		generator::generators::{generate_comment, generate_line, generate_script, generate_variable},
+		lexer::token::{Span, Token, TokenType},
+		program::{Expression, ScriptKind},
 	};
 
 	use super::generate_exit;
 	fn some(string: &str) -> Option<String> {
 		Some(string.to_string())
 	}
+	fn expression(tokens: &[(TokenType, &str)]) -> Expression {
+		Expression { tokens: tokens.iter().map(|(ttype, value)| Token::val(ttype.clone(), some(value))).collect() }
+	}
 
 	#[test]
-	fn test_generate_variable() -> Result<(), Error> {
-		let mut node = Node::single(NodeType::ASSIGN, Token::val(TokenType::ASSIGN, some("my_var")));
-		node.children = vec![Node::single(
-			NodeType::SYMBOL,
-			Token::val(TokenType::LIT_NUM, some("42")),
-		)];
-		let result = generate_variable(&node, false)?;
+	fn test_generate_variable() -> Result<(), DoitError> {
+		let mut value = expression(&[(TokenType::LIT_NUM, "42")]);
+		let result = generate_variable("my_var", &value, false)?;
 		assert_eq!(result, "double my_var = 42;\n");
-		let mut result = generate_variable(&node, true)?;
+		let mut result = generate_variable("my_var", &value, true)?;
 		assert_eq!(result, "my_var = 42;\n");
-		node.children = vec![Node::single(
-			NodeType::SYMBOL,
-			Token::val(TokenType::LIT_STR, some("Hello, world!")),
-		)];
-		result = generate_variable(&node, false)?;
+		value = expression(&[(TokenType::LIT_STR, "Hello, world!")]);
+		result = generate_variable("my_var", &value, false)?;
 		assert_eq!(result, "::std::string my_var = \"Hello, world!\";\n");
-		result = generate_variable(&node, true)?;
+		result = generate_variable("my_var", &value, true)?;
 		assert_eq!(result, "my_var = \"Hello, world!\";\n");
-		node.children = vec![
-			Node::single(NodeType::SYMBOL, Token::val(TokenType::LIT_NUM, some("42"))),
-			Node::single(NodeType::SYMBOL, Token::val(TokenType::SYMBOL, some("+"))),
-			Node::single(NodeType::SYMBOL, Token::val(TokenType::SYMBOL, some("other_var"))),
-		];
-		result = generate_variable(&node, false)?;
+		value = expression(&[(TokenType::LIT_NUM, "42"), (TokenType::SYMBOL, "+"), (TokenType::SYMBOL, "other_var")]);
+		result = generate_variable("my_var", &value, false)?;
 		assert_eq!(result, "double my_var = 42 + other_var;\n");
-		result = generate_variable(&node, true)?;
+		result = generate_variable("my_var", &value, true)?;
 		assert_eq!(result, "my_var = 42 + other_var;\n");
-		node.children = vec![
-			Node::single(NodeType::SYMBOL, Token::val(TokenType::LIT_STR, some("test"))),
-			Node::single(NodeType::SYMBOL, Token::val(TokenType::SYMBOL, some("+"))),
-			Node::single(NodeType::SYMBOL, Token::val(TokenType::SYMBOL, some("other_var"))),
-		];
-		result = generate_variable(&node, false)?;
+		value = expression(&[(TokenType::LIT_STR, "test"), (TokenType::SYMBOL, "+"), (TokenType::SYMBOL, "other_var")]);
+		result = generate_variable("my_var", &value, false)?;
 		assert_eq!(result, "::std::string my_var = \"test\" + other_var;\n");
-		result = generate_variable(&node, true)?;
+		result = generate_variable("my_var", &value, true)?;
 		assert_eq!(result, "my_var = \"test\" + other_var;\n");
 		Ok(())
 	}
 
 	#[test]
-	fn test_generate_script_sh() -> Result<(), Error> {
-		let node = Node::single(
-			NodeType::SCR_SH,
-			Token::val(TokenType::SCR_SH, some("echo hello world")),
-		);
-		let mut result = generate_script_sh(&node, &["var1"])?;
+	fn test_generate_script_sh() -> Result<(), DoitError> {
+		let mut result = generate_script(&ScriptKind::Shell, "echo hello world", &["var1"], 3)?;
 		assert_eq!(
 			result,
-			"__SYSTEM_SH(R\"__DOIT__(echo hello world)__DOIT__\", ::doit::args_map({__VAR(var1)}));\n"
+			"__SCRIPT('$', \"\", R\"__DOIT__(echo hello world)__DOIT__\", ::doit::args_map({__VAR(var1)}), 3);\n"
 		);
-		result = generate_script_sh(&node, &["var1", "var2", "var3"])?;
+		result = generate_script(&ScriptKind::Shell, "echo hello world", &["var1", "var2", "var3"], 12)?;
 		assert_eq!(
 			result,
-			"__SYSTEM_SH(R\"__DOIT__(echo hello world)__DOIT__\", ::doit::args_map({__VAR(var1),__VAR(var2),__VAR(var3)}));\n"
+			"__SCRIPT('$', \"\", R\"__DOIT__(echo hello world)__DOIT__\", ::doit::args_map({__VAR(var1),__VAR(var2),__VAR(var3)}), 12);\n"
 		);
 		Ok(())
 	}
 	#[test]
-	fn test_generate_script_py() -> Result<(), Error> {
-		let node = Node::single(
-			NodeType::SCR_SH,
-			Token::val(TokenType::SCR_SH, some("echo hello world")),
+	fn test_generate_script_py() -> Result<(), DoitError> {
+		let mut result = generate_script(&ScriptKind::Python, "print('hello world')", &["var1"], 3)?;
+		assert_eq!(
+			result,
+			"__SCRIPT('%', \"\", R\"__DOIT__(print('hello world'))__DOIT__\", ::doit::args_map({__VAR(var1)}), 3);\n"
 		);
-		let mut result = generate_script_py(&node, &["var1"])?;
+		result = generate_script(&ScriptKind::Python, "print('hello world')", &["var1", "var2", "var3"], 12)?;
 		assert_eq!(
 			result,
-			"__SYSTEM_PY(R\"__DOIT__(echo hello world)__DOIT__\", ::doit::args_map({__VAR(var1)}));\n"
+			"__SCRIPT('%', \"\", R\"__DOIT__(print('hello world'))__DOIT__\", ::doit::args_map({__VAR(var1),__VAR(var2),__VAR(var3)}), 12);\n"
 		);
-		result = generate_script_py(&node, &["var1", "var2", "var3"])?;
+		Ok(())
+	}
+	#[test]
+	fn test_generate_script_program() -> Result<(), DoitError> {
+		let kind = ScriptKind::Program(String::from("/usr/bin/env node"));
+		let result = generate_script(&kind, "console.log(1)", &[], 5)?;
 		assert_eq!(
 			result,
-			"__SYSTEM_PY(R\"__DOIT__(echo hello world)__DOIT__\", ::doit::args_map({__VAR(var1),__VAR(var2),__VAR(var3)}));\n"
+			"__SCRIPT('!', R\"__DOIT__(/usr/bin/env node)__DOIT__\", R\"__DOIT__(console.log(1))__DOIT__\", ::doit::args_map({}), 5);\n"
 		);
 		Ok(())
 	}
 
 	#[test]
-	fn test_generate_comment() -> Result<(), Error> {
-		let node = Node::single(NodeType::COMMENT, Token::val(TokenType::COMMENT, some(" comment")));
-		let node = generate_comment(&node)?;
-		assert_eq!(node, "// comment\n");
+	fn test_generate_comment() -> Result<(), DoitError> {
+		assert_eq!(generate_comment(" comment")?, "// comment\n");
 		Ok(())
 	}
 
 	#[test]
-	fn test_generate_exit() -> Result<(), Error> {
-		let mut node = Node::single(NodeType::EXIT, Token::sym(TokenType::EXIT));
-		node.children = vec![Node::single(
-			NodeType::SYMBOL,
-			Token::val(TokenType::LIT_NUM, some("42")),
-		)];
-		let result = generate_exit(&node)?;
+	fn test_generate_exit() -> Result<(), DoitError> {
+		let result = generate_exit(Some(&expression(&[(TokenType::LIT_NUM, "42")])))?;
 		assert_eq!(result, "::doit::exit(42);\n");
-		node.children = vec![
-			Node::single(NodeType::SYMBOL, Token::val(TokenType::SYMBOL, some("my_var"))),
-			Node::single(NodeType::SYMBOL, Token::val(TokenType::SYMBOL, some("+"))),
-			Node::single(NodeType::SYMBOL, Token::val(TokenType::LIT_NUM, some("21"))),
-		];
-		let result = generate_exit(&node)?;
+		let value = expression(&[(TokenType::SYMBOL, "my_var"), (TokenType::SYMBOL, "+"), (TokenType::LIT_NUM, "21")]);
+		let result = generate_exit(Some(&value))?;
 		assert_eq!(result, "::doit::exit(my_var + 21);\n");
+		assert_eq!(generate_exit(None)?, "::doit::exit();\n");
 		Ok(())
 	}
+
+	#[test]
+	fn test_generate_line() {
+		assert_eq!(generate_line(&Span::EMPTY, "/tmp/do.it"), "");
+		let span = Span::new(12, 2, 12, 5);
+		assert_eq!(generate_line(&span, "/tmp/do.it"), "#line 12 \"/tmp/do.it\"\n");
+		assert_eq!(generate_line(&span, "/tmp/\"quoted\"/do.it"), "#line 12 \"/tmp/\\\"quoted\\\"/do.it\"\n");
+	}
 }
@@ -1,173 +1,137 @@
-use std::{
-	fmt::Write,
-	io::{Error, ErrorKind},
-};
+use std::fmt::Write;
 
-use crate::parser::{
-	nodes::{Node, NodeType},
-	Parser,
+use crate::{
+	backend::{Backend, Project},
+	error::DoitError,
+	program::{Item, Program, Statement, StatementKind, Target, TargetArg},
 };
 
-use self::generators::{generate_comment, generate_exit, generate_yield, generate_script_sh, generate_script_py, generate_variable, node_value};
+use self::generators::{
+	generate_comment, generate_exit, generate_line, generate_script, generate_variable, generate_yield,
+};
 
 mod generators;
-mod sources;
+pub mod shell;
+pub mod sources;
 
-struct TargetArg {
-	pub required: bool,
-	pub arg_name: String,
-	pub arg_help: String,
-}
-struct Target {
-	pub target_name: String,
-	pub target_help: String,
-	pub target_args: Vec<TargetArg>,
-}
+/** Transpiles a [`Program`] into the C++ source of the targets binary, which is then compiled against the runtime. */
+pub struct CppBackend;
 
-pub struct Generator<'generator> {
-	pub parser: &'generator mut Parser<'generator>,
-}
+impl Backend for CppBackend {
+	fn name(&self) -> &'static str {
+		"cpp"
+	}
 
-impl Generator<'_> {
-	pub fn new<'new>(parser: &'new mut Parser<'new>) -> Generator<'new> {
-		Generator::<'new> { parser }
+	fn emit(&self, program: &Program) -> Result<Project, DoitError> {
+		Ok(Project::single(sources::SOURCE_FILENAME, CppBackend::generate(program)?))
 	}
-	#[allow(clippy::only_used_in_recursion)]
-	fn generate_scope(
-		&mut self,
-		indent: &str,
-		nodes: &Vec<Node>,
-		vars: &[&str],
-		tgts: &mut Vec<Target>,
-	) -> Result<String, Error> {
-		let mut result = String::new();
-		let mut locals: Vec<&str> = vec![];
-		locals.resize(vars.len(), "");
-		locals.copy_from_slice(vars);
-		for node in nodes {
-			match node.ntype {
-				NodeType::ROOT => return Err(Error::new(ErrorKind::InvalidData, "Unexpected ROOT node")),
-				NodeType::EXIT => {
-					result.push_str(indent);
-					result.push_str(&generate_exit(node)?);
-				}
-				NodeType::YIELD => {
-					result.push_str(indent);
-					result.push_str(&generate_yield()?);
-				}
-				NodeType::ASSIGN => {
-					let var_name = node.value.value.as_ref().unwrap();
-					let exists = locals.contains(&var_name.as_str());
-					if !exists {
-						locals.push(node.value.value.as_ref().unwrap());
+}
+
+impl CppBackend {
+	pub fn generate(program: &Program) -> Result<String, DoitError> {
+		let mut source = (sources::SOURCE_FILE).to_string();
+		let filename = &program.filename;
+
+		// Generate the definitions
+		let mut definitions = String::new();
+		let mut globals: Vec<&str> = vec![];
+		// Globals can not be reassigned, so each has the single line that it was declared on
+		let mut declared: Vec<(&str, usize)> = vec![];
+		for item in &program.items {
+			match item {
+				Item::Statement(statement) => {
+					definitions.push_str(&generate_statement(filename, "\t", statement, &mut globals)?);
+					if let StatementKind::Assign { name, .. } = &statement.kind {
+						declared.push((name, statement.span.start_line));
 					}
-					result.push_str(indent);
-					result.push_str(&generate_variable(node, exists)?);
-				}
-				NodeType::TARGET => {
-					let name = node_value(node).to_string();
-					result.push_str(&format!("{}void {}(int argc, const char *argv[]) {{\n", indent, &name));
-					tgts.push(Target{
-						target_name: name,
-						target_help: node.help
-							.as_ref()
-							.and_then(|h| h.value.as_ref())
-							.unwrap_or(&"\x1b[90m<No help defined>\x1b[0m".to_string())
-							.clone(),
-						target_args: node.children.iter()
-						.filter(|it| { it.ntype == NodeType::ARG_REQ || it.ntype == NodeType::ARG_OPT })
-						.map(|it| {
-							let required: bool;
-							if it.ntype == NodeType::ARG_REQ {
-								required = true;
-							} else if it.ntype == NodeType::ARG_OPT {
-								required = false;
-							} else {
-								panic!("invalid node type");
-							}
-							TargetArg{
-								required,
-								arg_name: it.value.value.clone().expect("argument missing name"),
-								arg_help: it.help.as_ref().and_then(|h| h.value.as_ref())
-										.unwrap_or(&"\x1b[90m<No help defined>\x1b[0m".to_string())
-										.clone(),
-							}
-						}).collect(),
-					});
-					result.push_str(&self.generate_scope(
-						&(indent.to_string() + "\t"),
-						&node.children,
-						&locals,
-						tgts,
-					)?);
-					result.push_str(&format!("{}}}\n", indent));
-				}
-				NodeType::SCR_SH => {
-					result.push_str(indent);
-					result.push_str(&generate_script_sh(node, &locals)?);
-				}
-				NodeType::SCR_PY => {
-					result.push_str(indent);
-					result.push_str(&generate_script_py(node, &locals)?);
 				}
-				NodeType::COMMENT => {
-					result.push_str(indent);
-					result.push_str(&generate_comment(node)?);
+				Item::Target(target) => {
+					definitions.push_str(&generate_line(&target.span, filename));
+					definitions.push_str(&format!("\tvoid {}(int argc, const char *argv[]) {{\n", target.name));
+					definitions.push_str("\t\t__TARGET();\n");
+					// The globals that the target sees are traced as it starts, with the lines they were declared on
+					for (name, line) in &declared {
+						definitions.push_str(&format!("\t\t__ASSIGNED({name}, {line});\n"));
+					}
+					let mut locals = globals.clone();
+					for statement in &target.statements {
+						definitions.push_str(&generate_statement(filename, "\t\t", statement, &mut locals)?);
+						if let StatementKind::Assign { name, .. } = &statement.kind {
+							definitions.push_str(&format!("\t\t__ASSIGNED({name}, {});\n", statement.span.start_line));
+						}
+					}
+					definitions.push_str("\t}\n");
 				}
-				NodeType::SYMBOL => continue,
-				NodeType::ARG_OPT => continue,
-				NodeType::ARG_REQ => continue,
 			}
 		}
-		Ok(result)
-	}
-
-	pub fn generate(&mut self) -> Result<String, Error> {
-		let mut source = (sources::SOURCE_FILE).to_string();
-		let mut targets: Vec<Target> = vec![];
-		let root_node = self.parser.parse()?;
-
-		// Generate the definitions
-		let definitions = self.generate_scope("\t", &root_node.children, &[], &mut targets)?;
 		source = source.replace("{{{TARGET_DEFINITIONS}}}", &definitions);
+		source = source.replace("{{{DOIT_FILE}}}", filename);
 
 		// Generate the help
-		let root_help = match root_node.help.as_ref().and_then(|help| help.value.as_ref()) {
-			Some(v) => v.as_str(),
-			None => "",
-		};
-		source = source.replace("{{{ROOT_HELP}}}", root_help);
+		source = source.replace("{{{ROOT_HELP}}}", program.help.as_deref().unwrap_or_default());
 
-		let help_text = targets
-			.iter()
-			.fold(String::new(), |mut output: String, target: &Target| {
-				write!(output, "\n\t\t__HELP({}, R\"__DOIT__({})__DOIT__\"", target.target_name, target.target_help)
-					.expect("failed to generate code");
-				output = target.target_args.iter().fold(output, |mut output: String, arg: &TargetArg| {
-					write!(
-						output,
-						", __ARG({}, R\"__DOIT__({})__DOIT__\", R\"__DOIT__({})__DOIT__\")",
-						arg.required, arg.arg_name, arg.arg_help
-					).expect("failed to generate code");
-					output
-				});
-				write!(output, "),").expect("failed to generate code");
+		let help_text = program.targets().fold(String::new(), |mut output: String, target: &Target| {
+			write!(output, "\n\t\t__HELP({}, R\"__DOIT__({})__DOIT__\"", target.name, target.help)
+				.expect("failed to generate code");
+			output = target.args.iter().fold(output, |mut output: String, arg: &TargetArg| {
+				write!(
+					output,
+					", __ARG({}, R\"__DOIT__({})__DOIT__\", R\"__DOIT__({})__DOIT__\")",
+					arg.required, arg.name, arg.help
+				).expect("failed to generate code");
 				output
 			});
+			write!(output, "),").expect("failed to generate code");
+			output
+		});
 		source = source.replace("{{{TARGET_HELPS}}}", &help_text);
 
 		// Generate Target Matches
 		source = source.replace(
 			"{{{TARGET_MATCHES}}}",
-			&targets.iter().fold(
-				String::new(),
-				|mut output: String, tgt: &Target| {
-					let _ = write!(output, "\n\t__MATCH({});", tgt.target_name);
-					output
-				},
-			),
+			&program.targets().fold(String::new(), |mut output: String, target: &Target| {
+				let _ = write!(output, "\n\t__MATCH({});", target.name);
+				output
+			}),
 		);
 
-		Ok(sources::DOIT_HEADER.to_string() + &source)
+		Ok(reset_line_directive(sources::DOIT_HEADER.to_string() + &source))
+	}
+}
+
+/** Emits a single statement, where `locals` holds the variables that are already declared in its scope. */
+fn generate_statement<'a>(
+	filename: &str,
+	indent: &str,
+	statement: &'a Statement,
+	locals: &mut Vec<&'a str>,
+) -> Result<String, DoitError> {
+	let mut result = String::new();
+	if !matches!(statement.kind, StatementKind::Comment(_)) {
+		result.push_str(&generate_line(&statement.span, filename));
+	}
+	result.push_str(indent);
+	result.push_str(&match &statement.kind {
+		StatementKind::Exit(expression) => generate_exit(expression.as_ref())?,
+		StatementKind::Yield => generate_yield()?,
+		StatementKind::Assign { name, expression } => {
+			let exists = locals.contains(&name.as_str());
+			if !exists {
+				locals.push(name);
+			}
+			generate_variable(name, expression, exists)?
+		}
+		StatementKind::Script { kind, script } => generate_script(kind, script, locals, statement.span.start_line)?,
+		StatementKind::Comment(comment) => generate_comment(comment)?,
+	});
+	Ok(result)
+}
+
+/** Points g++ back at the generated file for everything that follows the do.it definitions. */
+fn reset_line_directive(source: String) -> String {
+	let mut lines: Vec<String> = source.split('\n').map(str::to_string).collect();
+	if let Some(index) = lines.iter().position(|line| line == "{{{LINE_RESET}}}") {
+		lines[index] = format!("#line {} \"{}\"", index + 2, sources::SOURCE_FILENAME);
 	}
+	lines.join("\n")
 }
@@ -0,0 +1,261 @@
+use crate::{
+	backend::{Backend, Project},
+	error::DoitError,
+	interpreter::{
+		self, Variables,
+		runtime::{self, Key, Piece},
+	},
+	program::{Item, Program, ScriptKind, StatementKind, Target},
+};
+
+/** Helpers shared by every target of the exported script. */
+const SHELL_RUNTIME: &str = r#"__doit_status=0
+
+# Appends the arguments <first> to <last> (or to the end when <last> is -) of a target to __doit_command, or the
+# fallback when the range is empty. Called as: __doit_range <fallback> <first> <last> <target> [args...]
+__doit_range() {
+	__doit_fallback=$1 __doit_first=$2 __doit_last=$3
+	shift 3
+	if [ "$__doit_last" = - ]; then
+		__doit_last=$(($# - 1))
+	fi
+	if [ "$__doit_first" -ge "$__doit_last" ] || [ "$__doit_first" -lt 0 ]; then
+		__doit_command=$__doit_command$__doit_fallback
+		return
+	fi
+	__doit_index=0 __doit_separator=
+	for __doit_arg in "$@"; do
+		if [ "$__doit_index" -ge "$__doit_first" ] && [ "$__doit_index" -le "$__doit_last" ]; then
+			__doit_command=$__doit_command$__doit_separator$__doit_arg
+			__doit_separator=' '
+		fi
+		__doit_index=$((__doit_index + 1))
+	done
+}
+
+__doit_python() {
+	${DOIT_PYTHON:-python3} -c "$1"
+}
+
+# Runs a script from a file with a program that is split on whitespace. Called as: __doit_program <program> <script>
+__doit_program() {
+	__doit_file=$(mktemp "${TMPDIR:-/tmp}/doit-XXXXXX") || return 127
+	printf '%s\n' "$2" > "$__doit_file"
+	$1 "$__doit_file"
+	__doit_program_status=$?
+	rm -f "$__doit_file"
+	return "$__doit_program_status"
+}
+"#;
+
+const INVALID_TARGET: &str =
+	r"\033[91mInvalid target name: \033[33m%s\033[0m\n\033[32mUsage: \033[34mdoit <target> [args...]\033[0m\n";
+
+/**
+Converts a do.it file into a standalone POSIX shell script, for running the targets where doit is not installed.
+
+Variables can only ever be assigned from literals and other variables, so their values are known up front and are
+folded straight into the scripts that use them. The CLI arguments are injected when the script runs.
+*/
+pub struct ShellBackend;
+
+impl Backend for ShellBackend {
+	fn name(&self) -> &'static str {
+		"sh"
+	}
+
+	fn emit(&self, program: &Program) -> Result<Project, DoitError> {
+		Ok(Project::single("do.sh", ShellBackend::generate(program)?))
+	}
+}
+
+impl ShellBackend {
+	pub fn generate(program: &Program) -> Result<String, DoitError> {
+		let mut globals: Variables = vec![];
+		let mut targets: Vec<&Target> = vec![];
+		let mut bodies: Vec<String> = vec![];
+		for item in &program.items {
+			match item {
+				Item::Statement(statement) => {
+					if let StatementKind::Assign { name, expression } = &statement.kind {
+						interpreter::assign(&mut globals, name, expression, statement.span)?;
+					}
+				}
+				Item::Target(target) => {
+					bodies.push(generate_target(target, globals.clone())?);
+					targets.push(target);
+				}
+			}
+		}
+
+		let mut script = format!(
+			"#!/bin/sh\n# Generated by doit v{} from {}\n# Regenerate it with `doit --emit sh` instead of editing it by hand.\n\n",
+			env!("CARGO_PKG_VERSION"),
+			program.filename
+		);
+		script.push_str(SHELL_RUNTIME);
+		for (index, (target, body)) in targets.iter().zip(&bodies).enumerate() {
+			script.push_str(&format!("\n# {}\n__doit_target_{index}() {{\n{body}}}\n", target.name));
+		}
+
+		let root_help = program.help.as_deref().unwrap_or_default();
+		let help = runtime::render_help(root_help, targets.iter().map(|target| (*target).clone()).collect());
+		script.push_str(&format!("\n__doit_help() {{\n\tprintf {}\n}}\n\n", quote(&printf_format(&help))));
+
+		script.push_str("if [ $# -lt 1 ]; then\n\t__doit_help\n\texit 1\nfi\ncase $1 in\n\t--help) __doit_help ;;\n");
+		for (index, target) in targets.iter().enumerate() {
+			script.push_str(&format!("\t{}) shift; __doit_target_{index} \"$@\" ;;\n", quote(&target.name)));
+		}
+		script.push_str(&format!("\t*)\n\t\tprintf '{INVALID_TARGET}' \"$1\"\n\t\texit 1\n\t\t;;\nesac\n"));
+		script.push_str("exit \"$__doit_status\"\n");
+		Ok(script)
+	}
+}
+
+/** Emits the body of a target's shell function, with `variables` holding the globals declared above it. */
+fn generate_target(target: &Target, mut variables: Variables) -> Result<String, DoitError> {
+	let mut body = String::new();
+	for statement in &target.statements {
+		match &statement.kind {
+			StatementKind::Assign { name, expression } => {
+				interpreter::assign(&mut variables, name, expression, statement.span)?
+			}
+			StatementKind::Script { kind, script } => {
+				let Some(pieces) = runtime::split(script) else {
+					body.push_str(&format!("\tprintf '%s' {}\n\texit 1\n", quote("Unexpected '$' at end of line")));
+					continue;
+				};
+				body.push_str(&generate_command(&target.name, &pieces, &variables));
+				body.push_str(&match kind {
+					ScriptKind::Shell => String::from("\t${DOIT_SHELL:-/bin/sh} -c \"$__doit_command\"\n"),
+					ScriptKind::Python => String::from("\t__doit_python \"$__doit_command\"\n"),
+					ScriptKind::Program(program) => {
+						format!("\t__doit_program {} \"$__doit_command\"\n", quote(program))
+					}
+				});
+				body.push_str("\t__doit_status=$?\n");
+			}
+			StatementKind::Exit(expression) => {
+				match interpreter::exit_status(&variables, expression.as_ref(), statement.span)? {
+					status if status < 0 => body.push_str("\texit \"$__doit_status\"\n"),
+					status => body.push_str(&format!("\texit {}\n", status & 0xff)),
+				}
+			}
+			StatementKind::Yield => {
+				body.push_str("\tif [ \"$__doit_status\" -gt 0 ]; then exit \"$__doit_status\"; fi\n")
+			}
+			StatementKind::Comment(comment) => {
+				comment.lines().for_each(|line| body.push_str(&format!("\t#{line}\n")));
+			}
+		}
+	}
+	if body.is_empty() {
+		body.push_str("\t:\n");
+	}
+	Ok(body)
+}
+
+/** Builds the `__doit_command` that a script runs, injecting the arguments and the folded variable values. */
+fn generate_command(target: &str, pieces: &[Piece], variables: &Variables) -> String {
+	let variable = |key: &[u8]| {
+		let key = String::from_utf8_lossy(key);
+		variables.iter().find(|(name, _)| *name == key).map(|(_, value)| value.to_string()).unwrap_or_default()
+	};
+	let mut command = Command::default();
+	for piece in pieces {
+		let key = match piece {
+			Piece::Text(text) => {
+				command.literal.push_str(&String::from_utf8_lossy(text));
+				continue;
+			}
+			Piece::Key(key) => Key::new(key),
+		};
+		match key {
+			Key::Arg(0) => command.literal.push_str(target),
+			Key::Arg(index) => command.expand(&format!("\"${{{index}}}\"")),
+			Key::All => command.expand("\"$*\""),
+			Key::Count => command.expand("\"$#\""),
+			Key::Range(first, Some(last), key) if first >= last || first < 0 => command.literal.push_str(&variable(key)),
+			Key::Range(first, last, key) => {
+				command.flush();
+				let last = last.map(|last| last.to_string()).unwrap_or("-".to_string());
+				let fallback = quote(&variable(key));
+				command.lines.push(format!("\t__doit_range {fallback} {first} {last} {} \"$@\"\n", quote(target)));
+			}
+			Key::Variable(key) => command.literal.push_str(&variable(key)),
+		}
+	}
+	if !command.literal.is_empty() || !command.word.is_empty() || command.lines.is_empty() {
+		command.flush();
+	}
+	command.lines.concat()
+}
+
+/** The shell statements that build up `__doit_command`, see [`generate_command`]. */
+#[derive(Default)]
+struct Command {
+	lines: Vec<String>,
+	/** The shell word that is appended to the command next */
+	word: String,
+	/** Text that is quoted as a whole once it ends */
+	literal: String,
+}
+
+impl Command {
+	fn expand(&mut self, expansion: &str) {
+		self.quote_literal();
+		self.word.push_str(expansion);
+	}
+	fn quote_literal(&mut self) {
+		if !self.literal.is_empty() {
+			self.word.push_str(&quote(&self.literal));
+			self.literal.clear();
+		}
+	}
+	fn flush(&mut self) {
+		self.quote_literal();
+		let prefix = if self.lines.is_empty() { "" } else { "$__doit_command" };
+		let word = if self.word.is_empty() { "''" } else { self.word.as_str() };
+		self.lines.push(format!("\t__doit_command={prefix}{word}\n"));
+		self.word.clear();
+	}
+}
+
+/** Quotes text so that the shell reads it back byte for byte. */
+fn quote(text: &str) -> String {
+	format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/** Escapes text for use as a `printf` format, writing the escape character as `\033` to keep the script readable. */
+fn printf_format(text: &str) -> String {
+	text.replace('\\', "\\\\").replace('%', "%%").replace('\x1b', "\\033")
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::interpreter::{Value, runtime::split};
+
+	use super::{generate_command, printf_format, quote};
+
+	#[test]
+	fn test_quote() {
+		assert_eq!(quote("it's"), "'it'\\''s'");
+		assert_eq!(printf_format("\x1b[32m100%\x1b[0m\n"), "\\033[32m100%%\\033[0m\n");
+	}
+
+	#[test]
+	fn test_generate_command() {
+		let variables = vec![("name".to_string(), Value::Text("doit".to_string())), ("n".to_string(), Value::Number(2.0))];
+		let command = |script: &str| generate_command("build", &split(script).unwrap(), &variables);
+		assert_eq!(command("echo $name $n $missing."), "\t__doit_command='echo doit 2 .'\n");
+		assert_eq!(command("echo $0 $1 $@ $#"), "\t__doit_command='echo build '\"${1}\"' '\"$*\"' '\"$#\"\n");
+		assert_eq!(
+			command("echo $(2:) $(1:3)!"),
+			"\t__doit_command='echo '\n\t__doit_range '' 2 - 'build' \"$@\"\n\
+			\t__doit_command=$__doit_command' '\n\t__doit_range '' 1 3 'build' \"$@\"\n\
+			\t__doit_command=$__doit_command'!'\n"
+		);
+		assert_eq!(command("echo $(3:3)"), "\t__doit_command='echo '\n");
+		assert_eq!(command(""), "\t__doit_command=''\n");
+	}
+}
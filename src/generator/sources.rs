@@ -1,10 +1,16 @@
-pub const DOIT_HEADER: &str = r#"#include <unordered_map>
-#include <sstream>
-#include <regex>
+/**
+Declarations of the doit runtime, which is prepended to every generated source. The definitions live in
+[`RUNTIME_SOURCE`], which is compiled once into a cached object file rather than once per do.it file.
+*/
+pub const DOIT_HEADER: &str = r#"#include <cstdint>
+#include <string>
+#include <vector>
+#include <unordered_map>
 namespace doit {
-	int EXIT_CODE = 0;
-	void exit(int override = -1) { ::exit(override < 0 ? EXIT_CODE : override); }
-	void yield() { if (EXIT_CODE > 0) exit(EXIT_CODE); }
+	extern int EXIT_CODE;
+	void exit(int override = -1);
+	void end_target(int status);
+	void yield();
 	typedef ::std::unordered_map<::std::string, ::std::string> args_map;
 	inline ::std::string to_string(::std::string __val) { return __val; }
 	struct __target_help_args {
@@ -17,6 +23,97 @@ namespace doit {
 		::std::string target_help;
 		::std::vector<__target_help_args> target_args;
 	};
+	::std::string to_string(double __val);
+	::std::string trim(::std::string text);
+	void print_tabbed_text(const ::uint16_t tabwidth, const bool clip_start, const ::std::string &text);
+	::std::string inject(::std::string fmt, int argc, const char *argv[], args_map vars);
+	void print_help(const char *program, const ::std::string &root_help, ::std::vector<__target_help> targets);
+	void begin_target(const char *file, int argc, const char *argv[]);
+	void assigned(const char *name, const ::std::string &value, int line);
+	int run_script(const ::std::string &script, char kind, const char *program, int line);
+}
+"#;
+
+/** Definitions of the doit runtime, see [`DOIT_HEADER`]. */
+pub const RUNTIME_SOURCE: &str = r#"#include <cerrno>
+#include <cstdio>
+#include <cstdlib>
+#include <cstring>
+#include <algorithm>
+#include <chrono>
+#include <csignal>
+#include <iostream>
+#include <sstream>
+#include <regex>
+#include <poll.h>
+#include <sys/wait.h>
+#include <unistd.h>
+namespace doit {
+	int EXIT_CODE = 0;
+	// Run options are passed through the environment, see `RunOptions`. Each is removed once read, so that the
+	// scripts do not hand them on to a doit that they run
+	::std::string take_option(const char *name) {
+		const char *value = getenv(name);
+		::std::string result = value ? value : "";
+		unsetenv(name);
+		return result;
+	}
+	int option(const char *name) { return atoi(take_option(name).c_str()); }
+	const bool DRY_RUN = option("DOIT_DRY_RUN") > 0;
+	const int VERBOSE = option("DOIT_VERBOSE");
+	const bool CAPTURE = option("DOIT_CAPTURE") > 0;
+	const ::std::string EVENTS = take_option("DOIT_EVENTS");
+	// The program that runs a kind of script, split on whitespace so that it may carry options, ie. `bash -eo pipefail`,
+	// which `!!!` blocks name themselves and the others read from the environment
+	::std::vector<::std::string> interpreter(char kind, const char *program) {
+		const char *value = kind == '!' ? program : getenv(kind == '%' ? "DOIT_PYTHON" : "DOIT_SHELL");
+		::std::istringstream is(value && *value ? value : kind == '%' ? "python3" : "/bin/sh");
+		::std::vector<::std::string> words;
+		for (::std::string word; is >> word;) words.push_back(word);
+		return words;
+	}
+	const char *DOIT_FILE = "";
+	const char *TARGET = nullptr;
+	::std::string json(const ::std::string &text) {
+		::std::ostringstream os;
+		os << '"';
+		for (unsigned char c : text) {
+			if (c == '"' || c == '\\') os << '\\' << c;
+			else if (c == '\n') os << "\\n";
+			else if (c == '\r') os << "\\r";
+			else if (c == '\t') os << "\\t";
+			else if (c < 0x20) {
+				char escaped[7];
+				snprintf(escaped, sizeof(escaped), "\\u%04x", c);
+				os << escaped;
+			} else os << c;
+		}
+		os << '"';
+		return os.str();
+	}
+	// Appends to the events file that doit builds its reports from, see `events.rs`
+	void event(const char *kind, const ::std::string &name, int line, int status, int argc = 0, const char *argv[] = nullptr,
+		const ::std::string &output = "", const ::std::string &error = "") {
+		static FILE *events = EVENTS.empty() ? nullptr : fopen(EVENTS.c_str(), "a");
+		if (!events) return;
+		auto now = ::std::chrono::duration_cast<::std::chrono::microseconds>(::std::chrono::system_clock::now().time_since_epoch());
+		::std::string args;
+		for (int i = 0; i < argc; ++i) args += (i ? "," : "") + json(argv[i]);
+		fprintf(events, "{\"event\":\"%s\",\"time\":%lld,\"name\":%s,\"line\":%d,\"status\":%d,\"args\":[%s],\"output\":%s,\"error\":%s}\n",
+			kind, (long long)now.count(), json(name).c_str(), line, status, args.c_str(), json(output).c_str(),
+			json(error).c_str());
+		fflush(events);
+	}
+	void end_target(int status) {
+		if (TARGET && !DRY_RUN) event("target_end", TARGET, 0, status);
+		TARGET = nullptr;
+	}
+	void exit(int override) {
+		int status = override < 0 ? EXIT_CODE : override;
+		end_target(status);
+		::exit(status);
+	}
+	void yield() { if (EXIT_CODE > 0) exit(EXIT_CODE); }
 	::std::string to_string(double __val) {
 		auto result = ::std::to_string(__val);
 		size_t end = result.find_last_not_of('0');
@@ -24,7 +121,7 @@ namespace doit {
 		result.erase(end, ::std::string::npos);
 		return result;
 	}
-	inline ::std::string trim(::std::string text) {
+	::std::string trim(::std::string text) {
 		auto first = text.find_first_not_of(" \t\n\r\f\v");
 		auto last = text.find_last_not_of(" \t\n\r\f\v");
 		if (first == ::std::string::npos || last == ::std::string::npos) return text;
@@ -130,82 +227,251 @@ namespace doit {
 		}
 		return os.str();
 	}
+	void print_help(const char *program, const ::std::string &root_help, ::std::vector<__target_help> targets) {
+		::std::string line;
+		::std::stringstream is;
+		printf("\e[32mUsage: \e[33m%s \e[34m<target> \e[90m[args...]\e[0m\n", program);
+		auto help_description = ::doit::trim(root_help);
+		if (help_description.size() > 0) {
+			is = ::std::stringstream(help_description);
+			while (::std::getline(is, line)) {
+				printf("       %s\n", line.c_str());
+			}
+		}
+		printf("\n\e[32mTARGETS\e[0m\n");
+		int largest = 0;
+		::std::sort(targets.begin(), targets.end(), [&largest](const ::doit::__target_help &a, const ::doit::__target_help &b) {
+			largest = ::std::max(largest, ::std::max((int)a.target_name.size(), (int)b.target_name.size()));
+			return a.target_name < b.target_name;
+		});
+		for (auto target : targets) {
+			printf("\e[34m  %*s\e[0m", largest, target.target_name.c_str());
+			if (target.target_args.size() == 0) {
+				::doit::print_tabbed_text(largest, true, target.target_help.c_str());
+				continue;
+			}
+			int largest_arg = 0;
+			for (auto arg : target.target_args) {
+				if (arg.required)
+					printf("\e[90m <%s>\e[0m", arg.arg_name.c_str());
+				else
+					printf("\e[90m [%s]\e[0m", arg.arg_name.c_str());
+				largest_arg = ::std::max(largest_arg, (int)arg.arg_name.size());
+			}
+			::std::cout << ::std::endl;
+			::doit::print_tabbed_text(largest, false, target.target_help.c_str());
+			for (auto arg : target.target_args) {
+				printf("\e[90m%*s\e[0m", largest + largest_arg + 4, arg.arg_name.c_str());
+				::doit::print_tabbed_text(largest + largest_arg + 2, true, arg.arg_help.c_str());
+			}
+		}
+	}
+	void begin_target(const char *file, int argc, const char *argv[]) {
+		DOIT_FILE = file;
+		TARGET = argv[0];
+		if (!DRY_RUN) event("target_start", TARGET, 0, 0, argc - 1, argv + 1);
+	}
+	void print_location(int line) { fprintf(stderr, "\e[36m%s:%d\e[0m ", DOIT_FILE, line); }
+	void assigned(const char *name, const ::std::string &value, int line) {
+		if (DRY_RUN) {
+			printf("\e[90m%s = %s\e[0m\n", name, value.c_str());
+		} else if (VERBOSE > 1) {
+			print_location(line);
+			fprintf(stderr, "\e[90m%s = %s\e[0m\n", name, value.c_str());
+		}
+	}
+	// Same names as `signals::name`
+	::std::string signal_name(int number) {
+		static const ::std::pair<int, const char *> NAMES[] = {{SIGHUP, "SIGHUP"}, {SIGINT, "SIGINT"},
+			{SIGQUIT, "SIGQUIT"}, {SIGABRT, "SIGABRT"}, {SIGKILL, "SIGKILL"}, {SIGSEGV, "SIGSEGV"}, {SIGPIPE, "SIGPIPE"},
+			{SIGALRM, "SIGALRM"}, {SIGTERM, "SIGTERM"}};
+		for (auto &[other, name] : NAMES) if (other == number) return name;
+		return "signal " + ::std::to_string(number);
+	}
+	// Installed while a script runs, so that the signals which kill it leave the runtime to report it
+	void outlive_signal(int) {}
+	// Runs a command with fork/exec and returns its wait status. With `output` and `error`, its stdout and stderr are
+	// each teed into them while still being written to the runtime's own stdout and stderr
+	int spawn(const ::std::vector<::std::string> &command, ::std::string *output, ::std::string *error) {
+		int fds[2][2];
+		bool capture = output && error;
+		if (capture && pipe(fds[0]) != 0) return 127 << 8;
+		if (capture && pipe(fds[1]) != 0) {
+			close(fds[0][0]);
+			close(fds[0][1]);
+			return 127 << 8;
+		}
+		pid_t pid = fork();
+		if (pid == 0) {
+			if (capture) {
+				dup2(fds[0][1], STDOUT_FILENO);
+				dup2(fds[1][1], STDERR_FILENO);
+				for (auto &ends : fds) close(ends[0]), close(ends[1]);
+			}
+			::std::vector<char *> argv;
+			for (auto &arg : command) argv.push_back(const_cast<char *>(arg.c_str()));
+			argv.push_back(nullptr);
+			execvp(argv[0], argv.data());
+			fprintf(stderr, "%s: %s\n", argv[0], strerror(errno));
+			_exit(127);
+		}
+		if (capture) {
+			close(fds[0][1]);
+			close(fds[1][1]);
+			FILE *streams[] = {stdout, stderr};
+			::std::string *captured[] = {output, error};
+			pollfd polled[] = {{fds[0][0], POLLIN, 0}, {fds[1][0], POLLIN, 0}};
+			char buffer[4096];
+			while (pid > 0 && (polled[0].fd >= 0 || polled[1].fd >= 0)) {
+				if (poll(polled, 2, -1) < 0) {
+					if (errno == EINTR) continue;
+					break;
+				}
+				for (int i = 0; i < 2; i++) {
+					if (polled[i].fd < 0 || !polled[i].revents) continue;
+					ssize_t size = read(polled[i].fd, buffer, sizeof(buffer));
+					if (size < 0 && errno == EINTR) continue;
+					// A closed pipe is ignored by poll once its descriptor is negative
+					if (size <= 0) {
+						polled[i].fd = -1;
+						continue;
+					}
+					fwrite(buffer, 1, size, streams[i]);
+					fflush(streams[i]);
+					captured[i]->append(buffer, size);
+				}
+			}
+			for (auto &ends : fds) close(ends[0]);
+		}
+		if (pid < 0) return 127 << 8;
+		int status = 0;
+		while (waitpid(pid, &status, 0) < 0 && errno == EINTR) {}
+		return status;
+	}
+	// Saves a script to a file for its program to run, returning an empty path if it could not be written
+	::std::string script_file(const ::std::string &script) {
+		const char *directory = getenv("TMPDIR");
+		::std::string path = ::std::string(directory && *directory ? directory : "/tmp") + "/doit-XXXXXX";
+		int fd = mkstemp(path.data());
+		if (fd < 0) return "";
+		::std::string text = script + "\n";
+		bool written = write(fd, text.data(), text.size()) == (ssize_t)text.size();
+		close(fd);
+		if (!written) unlink(path.c_str());
+		return written ? path : "";
+	}
+	void print_script(FILE *stream, char kind, const ::std::string &script) {
+		::std::string line;
+		::std::stringstream is(script);
+		::std::getline(is, line);
+		fprintf(stream, "\e[32m%c\e[0m %s\n", kind, line.c_str());
+		while (::std::getline(is, line)) fprintf(stream, "  %s\n", line.c_str());
+	}
+	int run_script(const ::std::string &script, char kind, const char *program, int line) {
+		if (DRY_RUN) {
+			print_script(stdout, kind, script);
+			return 0;
+		}
+		if (VERBOSE > 0) {
+			print_location(line);
+			print_script(stderr, kind, script);
+		}
+		fflush(stdout);
+		event("script_start", script, line, 0);
+		auto start = ::std::chrono::steady_clock::now();
+		auto command = interpreter(kind, program);
+		// `!!!` blocks are run from a file, since not every program can take a script as an argument
+		::std::string file = kind == '!' ? script_file(script) : "";
+		if (kind == '!') {
+			command.push_back(file);
+		} else {
+			command.push_back("-c");
+			command.push_back(script);
+		}
+		::std::string output, error;
+		// sigaction rather than signal, which resets the handler once it ran on some platforms
+		struct sigaction outlive = {}, interrupt, terminate;
+		outlive.sa_handler = outlive_signal;
+		outlive.sa_flags = SA_RESTART;
+		sigemptyset(&outlive.sa_mask);
+		sigaction(SIGINT, &outlive, &interrupt);
+		sigaction(SIGTERM, &outlive, &terminate);
+		int status = 127 << 8;
+		if (kind != '!' || !file.empty()) {
+			status = spawn(command, CAPTURE ? &output : nullptr, CAPTURE ? &error : nullptr);
+		} else {
+			fprintf(stderr, "doit: %s\n", strerror(errno));
+		}
+		if (!file.empty()) unlink(file.c_str());
+		sigaction(SIGINT, &interrupt, nullptr);
+		sigaction(SIGTERM, &terminate, nullptr);
+		int killed = WIFSIGNALED(status) ? WTERMSIG(status) : 0;
+		status = killed ? 128 + killed : WEXITSTATUS(status);
+		event("script_end", "", line, status, 0, nullptr, status ? output : "", status ? error : "");
+		if (killed) {
+			print_location(line);
+			fprintf(stderr, "\e[91mkilled by %s\e[0m\n", signal_name(killed).c_str());
+			exit(status);
+		}
+		if (VERBOSE > 0) {
+			::std::chrono::duration<double> elapsed = ::std::chrono::steady_clock::now() - start;
+			print_location(line);
+			fprintf(stderr, "\e[90mexited with %d after %.3fs\e[0m\n", status, elapsed.count());
+		}
+		return status;
+	}
 }
 "#;
 
-pub const SOURCE_FILE: &str = r#"#include <iostream>
+/** Name reported for the generated C++ file once the do.it `#line` mappings end. */
+pub const SOURCE_FILENAME: &str = "targets.cpp";
+
+pub const SOURCE_FILE: &str = r#"#include <cstdio>
+#include <cstdlib>
 #include <cstring>
-#include <vector>
-#include <algorithm>
-#include <sys/wait.h>
 
 #define __VAR(variable) {#variable, ::doit::to_string(variable)}
-#define __SYSTEM_SH(statement, vars) ::doit::EXIT_CODE = WEXITSTATUS(system(::doit::inject(statement, argc, argv, vars).c_str()))
-#define __SYSTEM_PY(statement, vars) ::doit::EXIT_CODE = WEXITSTATUS(system(("cat <<__EOF__ | python3\n" + ::doit::inject(statement, argc, argv, vars) + "\n__EOF__\n").c_str()))
+#define __SCRIPT(kind, program, statement, vars, line) ::doit::EXIT_CODE = ::doit::run_script(::doit::inject(statement, argc, argv, vars), kind, program, line)
+#define __TARGET() ::doit::begin_target(__doit_file, argc, argv)
+#define __ASSIGNED(variable, line) ::doit::assigned(#variable, ::doit::to_string(variable), line)
+static const char *__doit_file = R"__DOIT__({{{DOIT_FILE}}})__DOIT__";
 namespace script {
 {{{TARGET_DEFINITIONS}}}
 }
+{{{LINE_RESET}}}
 #undef __VAR
 #undef __VARS
-#undef __SYSTEM
+#undef __SCRIPT
+#undef __TARGET
+#undef __ASSIGNED
 
 #define __ARG(req, arg, help) ::doit::__target_help_args{req, arg, ::doit::trim(help)}
 #define __HELP(target, help, ...) {#target, ::doit::trim(help), {__VA_ARGS__}}
-void print_help() {
-	::std::string line;
-	::std::stringstream is;
-	printf("\e[32mUsage: \e[33mdoit \e[34m<target> \e[90m[args...]\e[0m\n");
-	auto help_description = ::doit::trim(R"__DOIT__({{{ROOT_HELP}}})__DOIT__");
-	if (help_description.size() > 0) {
-		is = ::std::stringstream(help_description);
-		while (::std::getline(is, line)) {
-			printf("       %s\n", line.c_str());
-		}
-	}
-	printf("\n\e[32mTARGETS\e[0m\n");
+// Named after the binary, which doit runs as `doit` and `doit --export` lets users rename
+const char *program_name(const char *argv0) {
+	const char *slash = strrchr(argv0, '/');
+	return slash ? slash + 1 : argv0;
+}
+void print_help(const char *program) {
 	::std::vector<::doit::__target_help> targets = {{{{TARGET_HELPS}}}
 	};
-	int largest = 0;
-	::std::sort(targets.begin(), targets.end(), [&largest](const ::doit::__target_help &a, const ::doit::__target_help &b) {
-		largest = ::std::max(largest, ::std::max((int)a.target_name.size(), (int)b.target_name.size()));
-		return a.target_name < b.target_name;
-	});
-	for (auto target : targets) {
-		printf("\e[34m  %*s\e[0m", largest, target.target_name.c_str());
-		if (target.target_args.size() == 0) {
-			::doit::print_tabbed_text(largest, true, target.target_help.c_str());
-			continue;
-		}
-		int largest_arg = 0;
-		for (auto arg : target.target_args) {
-			if (arg.required)
-				printf("\e[90m <%s>\e[0m", arg.arg_name.c_str());
-			else
-				printf("\e[90m [%s]\e[0m", arg.arg_name.c_str());
-			largest_arg = ::std::max(largest_arg, (int)arg.arg_name.size());
-		}
-		::std::cout << ::std::endl;
-		::doit::print_tabbed_text(largest, false, target.target_help.c_str());
-		for (auto arg : target.target_args) {
-			printf("\e[90m%*s\e[0m", largest + largest_arg + 4, arg.arg_name.c_str());
-			::doit::print_tabbed_text(largest + largest_arg + 2, true, arg.arg_help.c_str());
-		}
-	}
+	::doit::print_help(program, R"__DOIT__({{{ROOT_HELP}}})__DOIT__", targets);
 }
 #undef __HELP
 
 #define __MATCH(pattern) else if (!strcmp(argv[1], #pattern)) ::script::pattern(argc - 1, argv + 1)
 int main(int argc, const char *argv[]) {
 	if (argc < 2) {
-		print_help();
+		print_help(program_name(argv[0]));
 		return EXIT_FAILURE;
 	}
 	if (!strcmp(argv[1], "--help"))
-		print_help();{{{TARGET_MATCHES}}}
+		print_help(program_name(argv[0]));{{{TARGET_MATCHES}}}
 	else {
-		printf("\e[91mInvalid target name: \e[33m%s\e[0m\n\e[32mUsage: \e[34mdoit <target> [args...]\e[0m\n", argv[1]);
+		printf("\e[91mInvalid target name: \e[33m%s\e[0m\n\e[32mUsage: \e[34m%s <target> [args...]\e[0m\n", argv[1], program_name(argv[0]));
 		return EXIT_FAILURE;
 	}
+	::doit::end_target(::doit::EXIT_CODE);
 	return ::doit::EXIT_CODE;
 }
 #undef MATCH
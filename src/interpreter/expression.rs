@@ -0,0 +1,279 @@
+use crate::{
+	diagnostics::{Diagnostic, codes},
+	error::DoitError,
+	lexer::token::{Span, Token, TokenType},
+};
+
+use super::runtime;
+
+/** The value of a do.it variable, which the C++ backend declares as either a `double` or a `std::string`. */
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+	Number(f64),
+	Text(String),
+}
+
+impl std::fmt::Display for Value {
+	/** Formats the value the way `::doit::to_string` hands it to a script. */
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Value::Number(number) => f.write_str(&runtime::to_string(*number)),
+			Value::Text(text) => f.write_str(text),
+		}
+	}
+}
+
+/**
+Intermediate results keep C++'s distinction between integer and floating point arithmetic, and between string
+literals and `std::string`s.
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub enum Operand {
+	Int(i64),
+	Double(f64),
+	Text(String),
+	/** A string literal, which is a `const char *` in C++ until it is added to a `std::string` */
+	Literal(String),
+}
+
+impl Operand {
+	pub fn number(&self) -> Option<f64> {
+		match self {
+			Operand::Int(value) => Some(*value as f64),
+			Operand::Double(value) => Some(*value),
+			Operand::Text(_) | Operand::Literal(_) => None,
+		}
+	}
+	fn describe(&self) -> &'static str {
+		match self {
+			Operand::Int(_) => "int",
+			Operand::Double(_) => "double",
+			Operand::Text(_) => "std::string",
+			Operand::Literal(_) => "const char *",
+		}
+	}
+}
+
+/**
+Evaluates the tokens of an assignment or exit expression with the same semantics they have once pasted into the
+generated C++: integer literals use integer arithmetic, and strings may only be concatenated with other strings, of
+which at least one is a `std::string`.
+*/
+pub struct Evaluator<'a> {
+	tokens: &'a [Token],
+	index: usize,
+	span: Span,
+	lookup: &'a dyn Fn(&str) -> Option<Value>,
+}
+
+impl<'a> Evaluator<'a> {
+	pub fn new(tokens: &'a [Token], span: Span, lookup: &'a dyn Fn(&str) -> Option<Value>) -> Evaluator<'a> {
+		Evaluator { tokens, index: 0, span, lookup }
+	}
+
+	pub fn evaluate(mut self) -> Result<Operand, DoitError> {
+		let result = self.expression()?;
+		match (self.tokens.get(self.index), result) {
+			(Some(token), _) => Err(self.error(token.span, &format!("Unexpected `{}` in expression", value(token)))),
+			// The literal is converted once it is assigned to a `std::string` variable
+			(None, Operand::Literal(text)) => Ok(Operand::Text(text)),
+			(None, result) => Ok(result),
+		}
+	}
+
+	fn error(&self, span: Span, message: &str) -> DoitError {
+		let span = if span.is_empty() { self.span } else { span };
+		DoitError::Semantic(vec![Diagnostic::error(codes::INVALID_EXPRESSION, message, span)])
+	}
+
+	fn peek_symbol(&self) -> Option<&str> {
+		self.tokens
+			.get(self.index)
+			.filter(|token| token.ttype == TokenType::SYMBOL)
+			.map(value)
+	}
+
+	fn expression(&mut self) -> Result<Operand, DoitError> {
+		let mut left = self.term()?;
+		while let Some(operator) = self.peek_symbol().filter(|symbol| matches!(*symbol, "+" | "-")) {
+			let span = self.tokens[self.index].span;
+			let operator = operator.to_string();
+			self.index += 1;
+			let right = self.term()?;
+			left = self.binary(&operator, left, right, span)?;
+		}
+		Ok(left)
+	}
+
+	fn term(&mut self) -> Result<Operand, DoitError> {
+		let mut left = self.unary()?;
+		while let Some(operator) = self.peek_symbol().filter(|symbol| matches!(*symbol, "*" | "/")) {
+			let span = self.tokens[self.index].span;
+			let operator = operator.to_string();
+			self.index += 1;
+			let right = self.unary()?;
+			left = self.binary(&operator, left, right, span)?;
+		}
+		Ok(left)
+	}
+
+	fn unary(&mut self) -> Result<Operand, DoitError> {
+		if let Some(operator) = self.peek_symbol().filter(|symbol| matches!(*symbol, "+" | "-")) {
+			let span = self.tokens[self.index].span;
+			let negate = operator == "-";
+			self.index += 1;
+			return match self.unary()? {
+				Operand::Int(value) => Ok(Operand::Int(if negate { value.wrapping_neg() } else { value })),
+				Operand::Double(value) => Ok(Operand::Double(if negate { -value } else { value })),
+				Operand::Text(_) | Operand::Literal(_) => {
+					Err(self.error(span, "A string can not be used with a unary operator"))
+				}
+			};
+		}
+		self.primary()
+	}
+
+	fn primary(&mut self) -> Result<Operand, DoitError> {
+		let Some(token) = self.tokens.get(self.index) else {
+			return Err(self.error(Span::EMPTY, "Expected a value at the end of the expression"));
+		};
+		self.index += 1;
+		let text = value(token);
+		match token.ttype {
+			TokenType::LIT_NUM if text.contains('.') => {
+				text.parse().map(Operand::Double).map_err(|_| self.error(token.span, &format!("Invalid number `{text}`")))
+			}
+			TokenType::LIT_NUM => text
+				.parse()
+				.map(Operand::Int)
+				.map_err(|_| self.error(token.span, &format!("Invalid number `{text}`"))),
+			TokenType::LIT_STR => Ok(Operand::Literal(unescape(text))),
+			TokenType::NOMEN => match (self.lookup)(text) {
+				Some(Value::Number(value)) => Ok(Operand::Double(value)),
+				Some(Value::Text(value)) => Ok(Operand::Text(value)),
+				None => Err(self.error(token.span, &format!("'{text}' was not declared in this scope"))),
+			},
+			TokenType::SYMBOL if text == "(" => {
+				let result = self.expression()?;
+				match self.peek_symbol() {
+					Some(")") => {
+						self.index += 1;
+						Ok(result)
+					}
+					_ => Err(self.error(token.span, "Unclosed `(` in expression")),
+				}
+			}
+			_ => Err(self.error(token.span, &format!("Unexpected `{text}` in expression"))),
+		}
+	}
+
+	fn binary(&self, operator: &str, left: Operand, right: Operand, span: Span) -> Result<Operand, DoitError> {
+		match (left, right) {
+			(Operand::Literal(_), Operand::Literal(_)) if operator == "+" => Err(self.error(
+				span,
+				"Invalid operands to `+` (const char * and const char *), one side must be a string variable",
+			)),
+			(Operand::Text(left) | Operand::Literal(left), Operand::Text(right) | Operand::Literal(right))
+				if operator == "+" =>
+			{
+				Ok(Operand::Text(left + &right))
+			}
+			(Operand::Int(left), Operand::Int(right)) => match operator {
+				"+" => Ok(Operand::Int(left.wrapping_add(right))),
+				"-" => Ok(Operand::Int(left.wrapping_sub(right))),
+				"*" => Ok(Operand::Int(left.wrapping_mul(right))),
+				_ if right == 0 => Err(self.error(span, "Division by zero")),
+				_ => Ok(Operand::Int(left.wrapping_div(right))),
+			},
+			(left, right) => match (left.number(), right.number()) {
+				(Some(left), Some(right)) => Ok(Operand::Double(match operator {
+					"+" => left + right,
+					"-" => left - right,
+					"*" => left * right,
+					_ => left / right,
+				})),
+				_ => Err(self.error(
+					span,
+					&format!("Invalid operands to `{operator}` ({} and {})", left.describe(), right.describe()),
+				)),
+			},
+		}
+	}
+}
+
+fn value(token: &Token) -> &str {
+	token.value.as_deref().unwrap_or_default()
+}
+
+/** Applies the C++ escape sequences that string literals get once they are pasted into the generated source. */
+fn unescape(text: &str) -> String {
+	let mut result = String::new();
+	let mut chars = text.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			result.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('n') => result.push('\n'),
+			Some('t') => result.push('\t'),
+			Some('r') => result.push('\r'),
+			Some('a') => result.push('\x07'),
+			Some('b') => result.push('\x08'),
+			Some('f') => result.push('\x0c'),
+			Some('v') => result.push('\x0b'),
+			Some('e') => result.push('\x1b'),
+			Some('0') => result.push('\0'),
+			Some(other) => result.push(other),
+			None => result.push('\\'),
+		}
+	}
+	result
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::lexer::token::{Span, Token, TokenType};
+
+	use super::{Evaluator, Operand, Value};
+
+	fn evaluate(tokens: &[(TokenType, &str)]) -> Result<Operand, String> {
+		let tokens: Vec<Token> =
+			tokens.iter().map(|(ttype, value)| Token::val(ttype.clone(), Some(value.to_string()))).collect();
+		let lookup = |name: &str| match name {
+			"number" => Some(Value::Number(2.5)),
+			"text" => Some(Value::Text("abc".to_string())),
+			_ => None,
+		};
+		Evaluator::new(&tokens, Span::EMPTY, &lookup).evaluate().map_err(|err| err.to_string())
+	}
+
+	#[test]
+	fn test_evaluate() {
+		use TokenType::*;
+		assert_eq!(evaluate(&[(LIT_NUM, "42")]), Ok(Operand::Int(42)));
+		assert_eq!(evaluate(&[(LIT_NUM, "7"), (SYMBOL, "/"), (LIT_NUM, "2")]), Ok(Operand::Int(3)));
+		assert_eq!(evaluate(&[(LIT_NUM, "7"), (SYMBOL, "/"), (LIT_NUM, "2.0")]), Ok(Operand::Double(3.5)));
+		assert_eq!(
+			evaluate(&[(LIT_NUM, "1"), (SYMBOL, "+"), (LIT_NUM, "2"), (SYMBOL, "*"), (NOMEN, "number")]),
+			Ok(Operand::Double(6.0))
+		);
+		assert_eq!(
+			evaluate(&[(SYMBOL, "-"), (SYMBOL, "("), (LIT_NUM, "1"), (SYMBOL, "+"), (LIT_NUM, "2"), (SYMBOL, ")")]),
+			Ok(Operand::Int(-3))
+		);
+		assert_eq!(evaluate(&[(LIT_STR, "x"), (SYMBOL, "+"), (NOMEN, "text")]), Ok(Operand::Text("xabc".to_string())));
+		assert_eq!(evaluate(&[(LIT_STR, "x")]), Ok(Operand::Text("x".to_string())));
+		assert!(evaluate(&[(LIT_STR, "x"), (SYMBOL, "+"), (LIT_NUM, "1")]).is_err());
+		let literals = evaluate(&[(SYMBOL, "("), (LIT_STR, "x"), (SYMBOL, ")"), (SYMBOL, "+"), (LIT_STR, "y")]);
+		assert!(literals.unwrap_err().contains("const char * and const char *"));
+		assert_eq!(
+			evaluate(&[(NOMEN, "text"), (SYMBOL, "+"), (LIT_STR, "x"), (SYMBOL, "+"), (LIT_STR, "y")]),
+			Ok(Operand::Text("abcxy".to_string()))
+		);
+		assert!(evaluate(&[(NOMEN, "missing")]).unwrap_err().contains("'missing' was not declared"));
+		assert!(evaluate(&[(LIT_NUM, "1"), (SYMBOL, "/"), (LIT_NUM, "0")]).is_err());
+		assert!(evaluate(&[(LIT_NUM, "1"), (LIT_NUM, "2")]).is_err());
+		assert!(evaluate(&[]).is_err());
+	}
+}
@@ -0,0 +1,345 @@
+use std::{
+	fs,
+	io::{self, Write},
+	os::unix::process::ExitStatusExt,
+	process::ExitStatus,
+	time::Instant,
+};
+
+use crate::{
+	diagnostics::{self, Diagnostic, codes},
+	error::DoitError,
+	events::{self, Event, EventKind},
+	lexer::token::{Span, TokenType},
+	options::RunOptions,
+	program::{Expression, Item, Program, ScriptKind, StatementKind, Target},
+	signals,
+};
+
+use self::expression::{Evaluator, Operand};
+pub(crate) use self::expression::Value;
+
+mod expression;
+pub(crate) mod runtime;
+
+/** Variables in declaration order, which is also the order the C++ backend hands them to a script. */
+pub(crate) type Variables = Vec<(String, Value)>;
+
+/**
+Runs a target of the do.it file directly from its program model, without generating or compiling any C++. Behaves the
+same as the compiled targets binary: the same help, the same script injection and the same exit codes.
+*/
+pub fn run(filename: &str, args: &[String], options: &RunOptions) -> Result<u8, DoitError> {
+	let source = fs::read_to_string(filename)?;
+	let result = Program::parse(filename, &source).and_then(|program| {
+		let globals = check(&program)?;
+		Ok(Interpreter { program: &program, globals, exit_code: 0, options }.main(args))
+	});
+	result.inspect_err(|error| diagnostics::emit(filename, &source, error.diagnostics()))
+}
+
+/**
+Evaluates every assignment and exit expression in the file, which can never have side effects, so that an invalid
+expression is reported up front the way the C++ compiler would, regardless of which target is run. Every [`Program`]
+is checked when it is built, so that each backend rejects the same expressions. Returns the globals.
+*/
+pub(crate) fn check(program: &Program) -> Result<Variables, DoitError> {
+	let mut globals: Variables = vec![];
+	for item in &program.items {
+		match item {
+			Item::Statement(statement) => {
+				if let StatementKind::Assign { name, expression } = &statement.kind {
+					assign(&mut globals, name, expression, statement.span)?;
+				}
+			}
+			Item::Target(target) => {
+				let mut locals = globals.clone();
+				for statement in &target.statements {
+					match &statement.kind {
+						StatementKind::Assign { name, expression } => {
+							assign(&mut locals, name, expression, statement.span)?
+						}
+						StatementKind::Exit(expression) => {
+							exit_status(&locals, expression.as_ref(), statement.span)?;
+						}
+						_ => {}
+					}
+				}
+			}
+		}
+	}
+	Ok(globals)
+}
+
+fn lookup(variables: &Variables) -> impl Fn(&str) -> Option<Value> {
+	move |name| variables.iter().find(|(variable, _)| variable == name).map(|(_, value)| value.clone())
+}
+
+fn evaluate(variables: &Variables, expression: &Expression, span: Span) -> Result<Operand, DoitError> {
+	let lookup = lookup(variables);
+	Evaluator::new(&expression.tokens, span, &lookup).evaluate()
+}
+
+fn invalid(span: Span, message: &str) -> DoitError {
+	DoitError::Semantic(vec![Diagnostic::error(codes::INVALID_EXPRESSION, message, span)])
+}
+
+/** Declares or reassigns a variable, with the conversions of the `double` or `std::string` it would be in C++. */
+pub(crate) fn assign(
+	variables: &mut Variables,
+	name: &str,
+	expression: &Expression,
+	span: Span,
+) -> Result<(), DoitError> {
+	let operand = evaluate(variables, expression, span)?;
+	let number_literal = expression.tokens.first().is_some_and(|token| token.ttype == TokenType::LIT_NUM);
+	let existing = variables.iter().position(|(variable, _)| variable == name);
+	let value = match (existing.map(|index| &variables[index].1), operand) {
+		(Some(Value::Number(_)), Operand::Text(_)) => {
+			return Err(invalid(span, &format!("Can not assign a string to the number '{name}'")));
+		}
+		(Some(Value::Text(_)), operand @ (Operand::Int(_) | Operand::Double(_))) => {
+			// Assigning a number to a std::string stores it as a single character
+			let value = operand.number().unwrap_or_default();
+			Value::Text((value as i64 as u8 as char).to_string())
+		}
+		(None, Operand::Text(_)) if number_literal => {
+			return Err(invalid(span, &format!("Can not initialise the number '{name}' with a string")));
+		}
+		(None, Operand::Int(_) | Operand::Double(_)) if !number_literal => {
+			return Err(invalid(span, &format!("Can not initialise the string '{name}' with a number")));
+		}
+		(_, Operand::Text(text)) => Value::Text(text),
+		(_, operand) => Value::Number(operand.number().unwrap_or_default()),
+	};
+	match existing {
+		Some(index) => variables[index].1 = value,
+		None => variables.push((name.to_string(), value)),
+	}
+	Ok(())
+}
+
+/** The status passed to `::doit::exit`, where a negative status exits with the code of the last script. */
+pub(crate) fn exit_status(
+	variables: &Variables,
+	expression: Option<&Expression>,
+	span: Span,
+) -> Result<i32, DoitError> {
+	let Some(expression) = expression else {
+		return Ok(-1);
+	};
+	match evaluate(variables, expression, span)?.number() {
+		Some(status) => Ok(status as i32),
+		None => Err(invalid(span, "Can not exit with a string")),
+	}
+}
+
+struct Interpreter<'a> {
+	program: &'a Program,
+	globals: Variables,
+	exit_code: u8,
+	options: &'a RunOptions,
+}
+
+impl Interpreter<'_> {
+	/** Mirrors `main()` of the generated C++. */
+	fn main(&mut self, args: &[String]) -> u8 {
+		let Some(name) = args.first() else {
+			self.print_help();
+			return 1;
+		};
+		if name == "--help" {
+			self.print_help();
+			return self.exit_code;
+		}
+		let program = self.program;
+		let Some(position) = program
+			.items
+			.iter()
+			.position(|item| matches!(item, Item::Target(target) if target.name == *name))
+		else {
+			print!(
+				"\x1b[91mInvalid target name: \x1b[33m{name}\x1b[0m\n\x1b[32mUsage: \x1b[34mdoit <target> [args...]\x1b[0m\n"
+			);
+			let _ = io::stdout().flush();
+			return 1;
+		};
+		self.event(Event { args: args[1..].to_vec(), ..Event::now(EventKind::TargetStart, name, 0, 0) });
+		let status = self.run_target(position, args).unwrap_or(self.exit_code);
+		self.event(Event::now(EventKind::TargetEnd, name, 0, status as i32));
+		status
+	}
+
+	/** Runs the statements of the target at `position`, returning the exit code if the target exits early. */
+	fn run_target(&mut self, position: usize, argv: &[String]) -> Option<u8> {
+		let program = self.program;
+		let Item::Target(target) = &program.items[position] else {
+			return None;
+		};
+		// A target only sees the globals that are declared above it, which are traced as it starts
+		let visible: Vec<(&str, usize)> = program.items[..position]
+			.iter()
+			.filter_map(|item| match item {
+				Item::Statement(statement) => match &statement.kind {
+					StatementKind::Assign { name, .. } => Some((name.as_str(), statement.span.start_line)),
+					_ => None,
+				},
+				Item::Target(_) => None,
+			})
+			.collect();
+		let mut variables: Variables = vec![];
+		for (name, line) in visible {
+			let (_, value) = self.globals.iter().find(|(global, _)| global == name)?;
+			self.assigned(name, value, line);
+			variables.push((name.to_string(), value.clone()));
+		}
+		for statement in &target.statements {
+			match &statement.kind {
+				// Expressions were all checked before anything ran, so they can not fail here
+				StatementKind::Assign { name, expression } => {
+					assign(&mut variables, name, expression, statement.span).ok()?;
+					let (_, value) = variables.iter().find(|(variable, _)| variable == name)?;
+					self.assigned(name, value, statement.span.start_line);
+				}
+				StatementKind::Script { kind, script } => {
+					let vars: Vec<(String, String)> =
+						variables.iter().map(|(name, value)| (name.clone(), value.to_string())).collect();
+					let Some(script) = runtime::inject(script, argv, &vars) else {
+						print!("Unexpected '$' at end of line");
+						let _ = io::stdout().flush();
+						return Some(1);
+					};
+					match self.run_script(script, kind, statement.span.start_line) {
+						Ok(status) => self.exit_code = status,
+						Err(status) => return Some(status),
+					}
+				}
+				StatementKind::Exit(expression) => {
+					let status = exit_status(&variables, expression.as_ref(), statement.span).ok()?;
+					return Some(if status < 0 { self.exit_code } else { (status & 0xff) as u8 });
+				}
+				StatementKind::Yield if self.exit_code > 0 => return Some(self.exit_code),
+				_ => {}
+			}
+		}
+		None
+	}
+
+	/** Mirrors `::doit::run_script`, failing with the exit code of the target when the script was killed. */
+	fn run_script(&self, script: Vec<u8>, kind: &ScriptKind, line: usize) -> Result<u8, u8> {
+		if self.options.dry_run {
+			print!("{}", runtime::render_script(kind.symbol(), &String::from_utf8_lossy(&script)));
+			let _ = io::stdout().flush();
+			return Ok(0);
+		}
+		if self.options.verbose > 0 {
+			let rendered = runtime::render_script(kind.symbol(), &String::from_utf8_lossy(&script));
+			eprint!("{}{rendered}", self.location(line));
+		}
+		self.event(Event::now(EventKind::ScriptStart, &String::from_utf8_lossy(&script), line, 0));
+		let start = Instant::now();
+		let (status, output, error) = runtime::system(script, kind, self.options.capture);
+		let killed = status.as_ref().and_then(ExitStatus::signal);
+		let status = status.map(|status| signals::exit_code(&status)).unwrap_or(127);
+		let text = |bytes: &[u8]| if status > 0 { String::from_utf8_lossy(bytes).to_string() } else { String::new() };
+		let (output, error) = (text(&output), text(&error));
+		self.event(Event { output, error, ..Event::now(EventKind::ScriptEnd, "", line, status as i32) });
+		if let Some(signal) = killed {
+			eprintln!("{}\x1b[91mkilled by {}\x1b[0m", self.location(line), signals::name(signal));
+			return Err(status);
+		}
+		if self.options.verbose > 0 {
+			let elapsed = start.elapsed().as_secs_f64();
+			eprintln!("{}\x1b[90mexited with {status} after {elapsed:.3}s\x1b[0m", self.location(line));
+		}
+		Ok(status)
+	}
+
+	/** Mirrors `::doit::assigned`, which shows the value of a variable during a dry run or with `-x`. */
+	fn assigned(&self, name: &str, value: &Value, line: usize) {
+		if self.options.dry_run {
+			println!("\x1b[90m{name} = {value}\x1b[0m");
+			let _ = io::stdout().flush();
+		} else if self.options.verbose > 1 {
+			eprintln!("{}\x1b[90m{name} = {value}\x1b[0m", self.location(line));
+		}
+	}
+
+	/** Mirrors `::doit::event`, which records nothing during a dry run. */
+	fn event(&self, event: Event) {
+		if let Some(path) = self.options.events.as_ref().filter(|_| !self.options.dry_run) {
+			let _ = events::record(path, &event);
+		}
+	}
+
+	fn location(&self, line: usize) -> String {
+		format!("\x1b[36m{}:{line}\x1b[0m ", self.program.filename)
+	}
+
+	fn print_help(&self) {
+		let targets: Vec<Target> = self.program.targets().cloned().collect();
+		print!("{}", runtime::render_help(self.program.help.as_deref().unwrap_or_default(), targets));
+		let _ = io::stdout().flush();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		error::DoitError,
+		program::{Item, Program, Statement, StatementKind},
+	};
+
+	use super::{Value, Variables, check};
+
+	fn parse(source: &str) -> Program {
+		Program::parse("do.it", source).unwrap()
+	}
+
+	fn assign(variables: &mut Variables, statement: &Statement) -> Result<(), DoitError> {
+		let StatementKind::Assign { name, expression } = &statement.kind else {
+			panic!("not an assignment");
+		};
+		super::assign(variables, name, expression, statement.span)
+	}
+
+	#[test]
+	fn test_assign() -> Result<(), DoitError> {
+		let program = parse("a = 7 / 2\nb = 2 * a\nx = \"x\"\nc = x + \"y\"\nt {\n\tc = 65\n\tb = 1.5\n}\n");
+		let mut variables: Variables = vec![];
+		for item in &program.items[..4] {
+			let Item::Statement(statement) = item else { panic!("not a statement") };
+			assign(&mut variables, statement)?;
+		}
+		assert_eq!(variables[0], ("a".to_string(), Value::Number(3.0)));
+		assert_eq!(variables[1], ("b".to_string(), Value::Number(6.0)));
+		assert_eq!(variables[3], ("c".to_string(), Value::Text("xy".to_string())));
+		let target = program.targets().next().unwrap();
+		assign(&mut variables, &target.statements[0])?;
+		assign(&mut variables, &target.statements[1])?;
+		assert_eq!(variables[3].1, Value::Text("A".to_string()));
+		assert_eq!(variables[1].1, Value::Number(1.5));
+		// Two literals are two `const char *`s in C++, which g++ can not add
+		let error = Program::parse("do.it", "c = \"x\" + \"y\"\n").err().unwrap();
+		assert!(matches!(error, DoitError::Semantic(_)));
+		assert_eq!(error.diagnostics()[0].code, Some("E006"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_check() {
+		assert_eq!(check(&parse("a = 1\nt {\n\tb = 1 + a\n\texit b\n}\n")).unwrap().len(), 1);
+		let invalid = [
+			"t {\n\texit missing\n}\n",
+			"a = 1\nt {\n\ta = \"text\"\n}\n",
+			"a = \"text\"\nb = 1 + a\n",
+			"t {\n\texit \"text\"\n}\n",
+			"t {\n\tx = 1\n}\nu {\n\texit x\n}\n",
+		];
+		// Every program is checked as it is built, so that each backend rejects the same expressions
+		for source in invalid {
+			let error = Program::parse("do.it", source).err().unwrap();
+			assert_eq!(error.diagnostics()[0].code, Some("E006"), "{source}");
+		}
+	}
+}
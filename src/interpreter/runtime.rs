@@ -0,0 +1,484 @@
+use std::{
+	env,
+	ffi::OsString,
+	fs::{self, OpenOptions},
+	io::{self, Read, Write},
+	os::unix::{ffi::OsStringExt, fs::OpenOptionsExt},
+	path::PathBuf,
+	process::{self, Command, ExitStatus},
+	sync::atomic::{AtomicUsize, Ordering},
+	thread,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+	options::RunOptions,
+	program::{ScriptKind, Target},
+	signals,
+};
+
+/** Port of `::doit::to_string(double)`, which prints six decimals and then strips the trailing zeros. */
+pub fn to_string(value: f64) -> String {
+	let mut result = if value.is_nan() {
+		if value.is_sign_negative() { "-nan".to_string() } else { "nan".to_string() }
+	} else {
+		format!("{value:.6}")
+	};
+	if let Some(end) = result.rfind(|c| c != '0') {
+		let end = if result.as_bytes()[end] != b'.' { end + 1 } else { end };
+		result.truncate(end);
+	}
+	result
+}
+
+/** Environment variable naming the shell that runs the scripts, see `::doit::interpreter` */
+pub const SHELL_ENV: &str = "DOIT_SHELL";
+/** Environment variable naming the python that runs the python scripts */
+pub const PYTHON_ENV: &str = "DOIT_PYTHON";
+
+const WHITESPACE: &[char] = &[' ', '\t', '\n', '\r', '\x0c', '\x0b'];
+
+/** Port of `::doit::trim`, which leaves text that is nothing but whitespace untouched. */
+pub fn trim(text: &str) -> &str {
+	match (text.find(|c| !WHITESPACE.contains(&c)), text.rfind(|c| !WHITESPACE.contains(&c))) {
+		(Some(first), Some(last)) => &text[first..=last],
+		_ => text,
+	}
+}
+
+/** Splits text the same way repeated calls to `std::getline` do, which never yields a trailing empty line. */
+fn getlines(text: &str) -> Vec<&str> {
+	let mut lines: Vec<&str> = text.split('\n').collect();
+	if lines.last() == Some(&"") {
+		lines.pop();
+	}
+	lines
+}
+
+/** Right aligns text by its length in bytes, the same as `printf("%*s")`. */
+fn pad(text: &str, width: usize) -> String {
+	format!("{}{}", " ".repeat(width.saturating_sub(text.len())), text)
+}
+
+fn print_tabbed_text(out: &mut String, tabwidth: usize, clip_start: bool, text: &str) {
+	let lines = getlines(text);
+	let mut lines = lines.iter();
+	if clip_start {
+		out.push_str(&format!("  {}\n", trim(lines.next().unwrap_or(&""))));
+	}
+	for line in lines {
+		out.push_str(&format!("  {}  {}\n", " ".repeat(tabwidth), trim(line)));
+	}
+}
+
+/** Port of `::doit::print_script`, which shows a script in place of running it during a dry run. */
+pub fn render_script(kind: char, script: &str) -> String {
+	let lines = getlines(script);
+	let mut out = format!("\x1b[32m{kind}\x1b[0m {}\n", lines.first().unwrap_or(&""));
+	lines.iter().skip(1).for_each(|line| out.push_str(&format!("  {line}\n")));
+	out
+}
+
+/** Port of `::doit::print_help`, rendered into a string so that it is written to stdout in one go. */
+pub fn render_help(root_help: &str, mut targets: Vec<Target>) -> String {
+	let mut out = String::from("\x1b[32mUsage: \x1b[33mdoit \x1b[34m<target> \x1b[90m[args...]\x1b[0m\n");
+	let description = trim(root_help);
+	if !description.is_empty() {
+		getlines(description).iter().for_each(|line| out.push_str(&format!("       {line}\n")));
+	}
+	out.push_str("\n\x1b[32mTARGETS\x1b[0m\n");
+	// The C++ runtime measures the names inside of the sort comparator, which never runs for a single target
+	let largest = match targets.len() {
+		0 | 1 => 0,
+		_ => targets.iter().map(|target| target.name.len()).max().unwrap_or(0),
+	};
+	targets.sort_by(|a, b| a.name.cmp(&b.name));
+	for target in &targets {
+		out.push_str(&format!("\x1b[34m  {}\x1b[0m", pad(&target.name, largest)));
+		if target.args.is_empty() {
+			print_tabbed_text(&mut out, largest, true, trim(&target.help));
+			continue;
+		}
+		let mut largest_arg = 0;
+		for arg in &target.args {
+			if arg.required {
+				out.push_str(&format!("\x1b[90m <{}>\x1b[0m", arg.name));
+			} else {
+				out.push_str(&format!("\x1b[90m [{}]\x1b[0m", arg.name));
+			}
+			largest_arg = largest_arg.max(arg.name.len());
+		}
+		out.push('\n');
+		print_tabbed_text(&mut out, largest, false, trim(&target.help));
+		for arg in &target.args {
+			out.push_str(&format!("\x1b[90m{}\x1b[0m", pad(&arg.name, largest + largest_arg + 4)));
+			print_tabbed_text(&mut out, largest + largest_arg + 2, true, trim(&arg.help));
+		}
+	}
+	out
+}
+
+/** Parses the leading digits of `text` like `atoll`, saturating instead of overflowing. */
+fn atoll(text: &[u8]) -> i64 {
+	text.iter()
+		.take_while(|c| c.is_ascii_digit())
+		.fold(0i64, |value, c| value.saturating_mul(10).saturating_add((c - b'0') as i64))
+}
+
+/** Finds the first `(\d+):(\d+)?` match in `key`, the same as the `std::regex_search` in the C++ runtime. */
+fn search_range(key: &[u8]) -> Option<(&[u8], &[u8])> {
+	let mut start = 0;
+	while start < key.len() {
+		if !key[start].is_ascii_digit() {
+			start += 1;
+			continue;
+		}
+		let colon = start + key[start..].iter().take_while(|c| c.is_ascii_digit()).count();
+		if key.get(colon) == Some(&b':') {
+			let last = &key[colon + 1..];
+			let digits = last.iter().take_while(|c| c.is_ascii_digit()).count();
+			return Some((&key[start..colon], &last[..digits]));
+		}
+		start = colon;
+	}
+	None
+}
+
+/** What a `$key` in a script refers to, decided the same way as the `get` lambda of `::doit::inject`. */
+#[derive(Debug, PartialEq)]
+pub enum Key<'a> {
+	/** `$1`, where `$0` is the target name */
+	Arg(i64),
+	/** `$@` */
+	All,
+	/** `$#` */
+	Count,
+	/** `$(1:3)` or `$(1:)`, which falls back to a variable named after the whole key when the range is empty */
+	Range(i64, Option<i64>, &'a [u8]),
+	Variable(&'a [u8]),
+}
+
+impl Key<'_> {
+	pub fn new(key: &[u8]) -> Key<'_> {
+		if key.iter().all(u8::is_ascii_digit) {
+			return Key::Arg(atoll(key));
+		}
+		if key == b"@" {
+			return Key::All;
+		}
+		if key == b"#" {
+			return Key::Count;
+		}
+		match search_range(key) {
+			Some((first, last)) => {
+				let last = if last.is_empty() { None } else { Some(atoll(last) as i32 as i64) };
+				Key::Range(atoll(first) as i32 as i64, last, key)
+			}
+			None => Key::Variable(key),
+		}
+	}
+}
+
+/** A piece of a script, split on the `$` injections in it. */
+#[derive(Debug, PartialEq)]
+pub enum Piece {
+	Text(Vec<u8>),
+	Key(Vec<u8>),
+}
+
+/**
+Splits a script on its `$name`, `$(name)`, `$1`, `$@`, `$#` and `$(1:3)` injections, the same way `::doit::inject` reads
+them. Returns `None` when the script ends in a lone `$`, which the C++ runtime treats as a fatal error.
+*/
+pub fn split(fmt: &str) -> Option<Vec<Piece>> {
+	let fmt = fmt.as_bytes();
+	let mut pieces: Vec<Piece> = vec![];
+	let mut text: Vec<u8> = vec![];
+	let mut token: Vec<u8> = vec![];
+	let mut parsing = false;
+	let mut bracketed = false;
+	let mut key = |text: &mut Vec<u8>, token: &mut Vec<u8>| {
+		pieces.push(Piece::Text(std::mem::take(text)));
+		pieces.push(Piece::Key(std::mem::take(token)));
+	};
+	let mut c = 0;
+	while c < fmt.len() {
+		if parsing {
+			if bracketed {
+				if fmt[c] == b')' {
+					bracketed = false;
+					parsing = false;
+					key(&mut text, &mut token);
+				} else {
+					token.push(fmt[c]);
+				}
+			} else if !fmt[c].is_ascii_alphanumeric() && !matches!(fmt[c], b'_' | b'@' | b'#') {
+				parsing = false;
+				key(&mut text, &mut token);
+				text.push(fmt[c]);
+			} else {
+				token.push(fmt[c]);
+			}
+		} else if fmt[c] != b'$' {
+			text.push(fmt[c]);
+		} else if c + 1 >= fmt.len() {
+			return None;
+		} else if fmt[c + 1] == b'$' {
+			c += 1;
+			text.push(b'$');
+		} else {
+			parsing = true;
+			if fmt[c + 1] == b'(' {
+				bracketed = true;
+				c += 1;
+			}
+		}
+		c += 1;
+	}
+	if !token.is_empty() {
+		key(&mut text, &mut token);
+	}
+	pieces.push(Piece::Text(text));
+	pieces.retain(|piece| piece != &Piece::Text(vec![]));
+	Some(pieces)
+}
+
+fn lookup(key: &[u8], argv: &[String], vars: &[(String, String)]) -> Vec<u8> {
+	let argc = argv.len() as i64;
+	let join = |args: &[String]| args.join(" ").into_bytes();
+	let variable = |key: &[u8]| {
+		vars.iter().find(|(name, _)| name.as_bytes() == key).map(|(_, value)| value.clone().into_bytes()).unwrap_or_default()
+	};
+	match Key::new(key) {
+		Key::Arg(index) if index >= argc => vec![],
+		Key::Arg(index) => argv[index as usize].clone().into_bytes(),
+		Key::All => join(&argv[1..]),
+		Key::Count => (argc - 1).to_string().into_bytes(),
+		Key::Range(first, last, key) => {
+			let last = last.unwrap_or(argc - 1);
+			if first < last && first >= 0 {
+				let size = (last + 1).min(argc);
+				if first < size { join(&argv[first as usize..size as usize]) } else { vec![] }
+			} else {
+				variable(key)
+			}
+		}
+		Key::Variable(key) => variable(key),
+	}
+}
+
+/** Port of `::doit::inject`, see [`split`]. */
+pub fn inject(fmt: &str, argv: &[String], vars: &[(String, String)]) -> Option<Vec<u8>> {
+	let mut os: Vec<u8> = vec![];
+	for piece in split(fmt)? {
+		match piece {
+			Piece::Text(text) => os.extend(text),
+			Piece::Key(key) => os.extend(lookup(&key, argv, vars)),
+		}
+	}
+	Some(os)
+}
+
+/**
+The program that runs a kind of script, which `DOIT_SHELL` or `DOIT_PYTHON` may replace along with its options, and
+which `!!!` blocks name themselves.
+*/
+pub fn interpreter(kind: &ScriptKind) -> Vec<String> {
+	let (variable, default) = match kind {
+		ScriptKind::Shell => (SHELL_ENV, "/bin/sh"),
+		ScriptKind::Python => (PYTHON_ENV, "python3"),
+		ScriptKind::Program(program) => return program.split_whitespace().map(str::to_string).collect(),
+	};
+	let program = env::var(variable).ok().filter(|program| !program.trim().is_empty()).unwrap_or(default.to_string());
+	program.split_whitespace().map(str::to_string).collect()
+}
+
+/**
+Saves a script to a file for its program to run, like `::doit::script_file`. As with `mkstemp`, the file is only
+readable by the user, and a path that already exists (ie. a symlink planted in a shared `/tmp`) is never opened.
+*/
+fn script_file(mut script: Vec<u8>) -> io::Result<PathBuf> {
+	static COUNT: AtomicUsize = AtomicUsize::new(0);
+	script.push(b'\n');
+	loop {
+		let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+		let name = format!("doit-{}-{}-{nanos:08x}", process::id(), COUNT.fetch_add(1, Ordering::SeqCst));
+		let path = env::temp_dir().join(name);
+		match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+			Ok(mut file) => {
+				return file.write_all(&script).map(|_| path.clone()).inspect_err(|_| {
+					let _ = fs::remove_file(&path);
+				});
+			}
+			Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+			Err(err) => return Err(err),
+		}
+	}
+}
+
+/**
+Runs a script with its [`interpreter`] the same way `::doit::spawn` does, returning its exit status if it could be
+started. With `capture`, its stdout and stderr are each teed into the returned bytes, in that order.
+*/
+pub fn system(script: Vec<u8>, kind: &ScriptKind, capture: bool) -> (Option<ExitStatus>, Vec<u8>, Vec<u8>) {
+	let _ = io::stdout().flush();
+	let program = interpreter(kind);
+	let mut command = Command::new(&program[0]);
+	command.args(&program[1..]);
+	for name in RunOptions::ENV_NAMES {
+		command.env_remove(name);
+	}
+	let mut readers = None;
+	if capture {
+		let (Ok((stdout, out_writer)), Ok((stderr, err_writer))) = (io::pipe(), io::pipe()) else {
+			return (None, vec![], vec![]);
+		};
+		command.stdout(out_writer).stderr(err_writer);
+		readers = Some((stdout, stderr));
+	}
+	// `!!!` blocks are run from a file, since not every program can take a script as an argument
+	let file = match kind {
+		ScriptKind::Program(_) => match script_file(script) {
+			Ok(path) => Some(path),
+			Err(err) => {
+				eprintln!("doit: {}", err.to_string().split(" (os error").next().unwrap_or_default());
+				return (None, vec![], vec![]);
+			}
+		},
+		_ => {
+			command.arg("-c").arg(OsString::from_vec(script));
+			None
+		}
+	};
+	command.args(&file);
+	let (mut output, mut error) = (vec![], vec![]);
+	let status = signals::run(command, |_| {
+		if let Some((stdout, stderr)) = readers {
+			// Read at the same time, so that a script which fills one pipe is never blocked behind the other
+			thread::scope(|scope| {
+				scope.spawn(|| tee(stderr, io::stderr(), &mut error));
+				tee(stdout, io::stdout(), &mut output);
+			});
+		}
+	});
+	if let Some(path) = file {
+		let _ = fs::remove_file(path);
+	}
+	// Printed the same way as the runtime's `strerror`, without the error number that std appends
+	let status = status.inspect_err(|err| {
+		eprintln!("{}: {}", program[0], err.to_string().split(" (os error").next().unwrap_or_default())
+	});
+	(status.ok(), output, error)
+}
+
+/** Copies everything from `pipe` to `stream` as it arrives, keeping a copy in `captured`. */
+fn tee(mut pipe: impl Read, mut stream: impl Write, captured: &mut Vec<u8>) {
+	let mut buffer = [0u8; 4096];
+	while let Ok(size @ 1..) = pipe.read(&mut buffer) {
+		let _ = stream.write_all(&buffer[..size]);
+		let _ = stream.flush();
+		captured.extend_from_slice(&buffer[..size]);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		lexer::token::Span,
+		program::{Target, TargetArg},
+	};
+
+	use std::{fs, os::unix::fs::PermissionsExt};
+
+	use super::{Key, Piece, inject, render_help, render_script, script_file, split, to_string, trim};
+
+	fn args(args: &[&str]) -> Vec<String> {
+		args.iter().map(|arg| arg.to_string()).collect()
+	}
+	fn injected(fmt: &str, argv: &[&str]) -> String {
+		let vars = vec![("my_var".to_string(), "value".to_string())];
+		String::from_utf8(inject(fmt, &args(argv), &vars).unwrap()).unwrap()
+	}
+
+	#[test]
+	fn test_to_string() {
+		assert_eq!(to_string(42.0), "42");
+		assert_eq!(to_string(42.5), "42.5");
+		assert_eq!(to_string(0.0), "0");
+		assert_eq!(to_string(-1.25), "-1.25");
+		assert_eq!(to_string(1.0 / 3.0), "0.333333");
+		assert_eq!(to_string(f64::INFINITY), "inf");
+	}
+
+	#[test]
+	fn test_trim() {
+		assert_eq!(trim("  a b \n"), "a b");
+		assert_eq!(trim(" \t "), " \t ");
+	}
+
+	#[test]
+	fn test_inject() {
+		let argv = ["tgt", "a", "b", "c", "d"];
+		assert_eq!(injected("echo $1 $2 $9", &argv), "echo a b ");
+		assert_eq!(injected("echo $0 $# $@", &argv), "echo tgt 4 a b c d");
+		assert_eq!(injected("echo $(1:3)|$(2:)|$(3: )|$(3:3)", &argv), "echo a b c|b c d|c d|");
+		assert_eq!(injected("echo $my_var $(my_var)s $$HOME $missing.", &argv), "echo value values $HOME .");
+		assert_eq!(injected("echo $my_var$1", &argv), "echo value$1");
+		assert_eq!(injected("$ x", &argv), "tgt x");
+		assert!(inject("echo $", &args(&argv), &[]).is_none());
+	}
+
+	#[test]
+	fn test_split() {
+		let text = |text: &str| Piece::Text(text.as_bytes().to_vec());
+		let key = |key: &str| Piece::Key(key.as_bytes().to_vec());
+		assert_eq!(split("echo $1-$(a b)$$").unwrap(), vec![text("echo "), key("1"), text("-"), key("a b"), text("$")]);
+		assert_eq!(Key::new(b"12"), Key::Arg(12));
+		assert_eq!(Key::new(b"3:"), Key::Range(3, None, b"3:"));
+		assert_eq!(Key::new(b"x1:2"), Key::Range(1, Some(2), b"x1:2"));
+		assert_eq!(Key::new(b"my_var"), Key::Variable(b"my_var"));
+	}
+
+	#[test]
+	fn test_render_script() {
+		assert_eq!(render_script('$', "ls"), "\x1b[32m$\x1b[0m ls\n");
+		assert_eq!(render_script('%', "a\n\tb\n"), "\x1b[32m%\x1b[0m a\n  \tb\n");
+		assert_eq!(render_script('$', ""), "\x1b[32m$\x1b[0m \n");
+	}
+
+	#[test]
+	fn test_render_help() {
+		let targets = vec![
+			Target {
+				name: "zz".to_string(),
+				help: "  Last\nsecond line\n".to_string(),
+				args: vec![],
+				statements: vec![],
+				span: Span::EMPTY,
+			},
+			Target {
+				name: "a".to_string(),
+				help: "First".to_string(),
+				args: vec![TargetArg { required: true, name: "file".to_string(), help: " The file ".to_string() }],
+				statements: vec![],
+				span: Span::EMPTY,
+			},
+		];
+		assert_eq!(
+			render_help(" Root help ", targets),
+			"\x1b[32mUsage: \x1b[33mdoit \x1b[34m<target> \x1b[90m[args...]\x1b[0m\n       Root help\n\n\x1b[32mTARGETS\x1b[0m\n\
+			\x1b[34m   a\x1b[0m\x1b[90m <file>\x1b[0m\n      First\n\x1b[90m      file\x1b[0m  The file\n\
+			\x1b[34m  zz\x1b[0m  Last\n      second line\n"
+		);
+	}
+
+	#[test]
+	fn test_script_file() {
+		let (first, second) = (script_file(b"puts 1".to_vec()).unwrap(), script_file(b"puts 2".to_vec()).unwrap());
+		assert_ne!(first, second);
+		assert_eq!(fs::read_to_string(&first).unwrap(), "puts 1\n");
+		assert_eq!(fs::metadata(&first).unwrap().permissions().mode() & 0o777, 0o600);
+		fs::remove_file(first).unwrap();
+		fs::remove_file(second).unwrap();
+	}
+}
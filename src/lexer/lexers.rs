@@ -1,11 +1,87 @@
+use core::fmt;
+
+use crate::diagnostics::codes;
+
 use super::{
-	token::TokenType,
+	token::{ScriptKind, TokenType},
 	validation::{is_nomenclature, is_number},
 };
 
+#[derive(Debug, PartialEq)]
+pub enum LexError {
+	EndOfFile,
+	UnterminatedString,
+	InvalidEscape,
+	/** A block delimiter was followed by other content on the same line */
+	TrailingContent(TokenType),
+	MissingBlockText(TokenType),
+	UnterminatedBlock(TokenType),
+	/** The lines of a block are indented with tabs on some lines and spaces on others, so it can not be dedented */
+	MixedIndentation(TokenType),
+	/** A `!!!` block neither names its program nor starts with a `#!` line */
+	MissingProgram,
+	/** A line starts with `!!`, which is neither a script nor the start of a `!!!` block */
+	UnknownProgramScript,
+}
+
+impl LexError {
+	pub fn code(&self) -> &'static str {
+		match self {
+			LexError::EndOfFile => codes::UNEXPECTED_EOF,
+			LexError::UnterminatedString | LexError::InvalidEscape => codes::MALFORMED_STRING,
+			LexError::UnknownProgramScript => codes::UNEXPECTED_TOKEN,
+			LexError::TrailingContent(_)
+			| LexError::MissingBlockText(_)
+			| LexError::UnterminatedBlock(_)
+			| LexError::MixedIndentation(_)
+			| LexError::MissingProgram => codes::MALFORMED_BLOCK,
+		}
+	}
+}
+
+impl fmt::Display for LexError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let block = |ttype: &TokenType| match ttype {
+			// The program of a `!!!` block is only known once its first line was read
+			TokenType::SCRIPT(ScriptKind::Program(_)) => String::from("SCRIPT(Program)"),
+			ttype => format!("{ttype:?}"),
+		};
+		match self {
+			LexError::EndOfFile => write!(f, "End of file"),
+			LexError::UnterminatedString => write!(f, "Unexpected EOF, string literal is missing its closing '\"'"),
+			LexError::InvalidEscape => write!(f, "Unexpected escape character"),
+			LexError::TrailingContent(ttype) => write!(
+				f,
+				"Did not find EOL. {} block symbols must not be followed by any other content",
+				block(ttype)
+			),
+			LexError::MissingBlockText(ttype) => {
+				write!(f, "Unexpected EOF when expecting {} block text", block(ttype))
+			}
+			LexError::UnterminatedBlock(ttype) => {
+				write!(f, "Unexpected EOF, {} block is missing its closing delimiter", block(ttype))
+			}
+			LexError::MixedIndentation(ttype) => write!(
+				f,
+				"{} block is indented with both tabs and spaces. Indent every line alike, or add `verbatim` \
+				after the opening delimiter to keep the indentation as written",
+				block(ttype)
+			),
+			LexError::MissingProgram => write!(
+				f,
+				"`!!!` blocks must name the program that runs them, ie. `!!! node`, or start with a `#!` line"
+			),
+			LexError::UnknownProgramScript => write!(
+				f,
+				"Expected `!!!` or a program name. Scripts for other programs are `!!!` blocks, ie. `!!! node`"
+			),
+		}
+	}
+}
+
 pub trait Consumer {
-	fn consume(&mut self) -> Result<char, String>;
-	fn consume_and_ignore(&mut self) -> Result<(), String>;
+	fn consume(&mut self) -> Result<char, LexError>;
+	fn consume_and_ignore(&mut self) -> Result<(), LexError>;
 	fn peek(&self) -> Option<char>;
 	fn look_ahead(&self, ahead: usize) -> Option<char>;
 }
@@ -21,14 +97,14 @@ impl VecCharConsumer<'_> {
 }
 
 impl Consumer for VecCharConsumer<'_> {
-	fn consume(&mut self) -> Result<char, String> {
+	fn consume(&mut self) -> Result<char, LexError> {
 		match self.data.get(self.position) {
 			Some(c) => { self.position += 1; Ok(*c) },
-			None => Err("end of string".to_string()),
+			None => Err(LexError::EndOfFile),
 		}
 	}
-	fn consume_and_ignore(&mut self) -> Result<(), String> {
-		if self.position >= self.data.len() { return Err("end of string".to_string()); }
+	fn consume_and_ignore(&mut self) -> Result<(), LexError> {
+		if self.position >= self.data.len() { return Err(LexError::EndOfFile); }
 		self.position += 1;
 		Ok(())
 	}
@@ -36,7 +112,7 @@ impl Consumer for VecCharConsumer<'_> {
 	fn look_ahead(&self, ahead: usize) -> Option<char> { self.data.get(self.position + (ahead - 1)).copied() }
 }
 
-pub fn scan_whitespace(consumer: &mut dyn Consumer) -> Result<(), String> {
+pub fn scan_whitespace(consumer: &mut dyn Consumer) -> Result<(), LexError> {
 	loop {
 		let next = consumer.peek().unwrap_or('\0');
 		if next == '\n' || !next.is_whitespace() {
@@ -47,7 +123,7 @@ pub fn scan_whitespace(consumer: &mut dyn Consumer) -> Result<(), String> {
 	Ok(())
 }
 
-pub fn conditional_reader(consumer: &mut dyn Consumer, predicate: fn(&char) -> bool) -> Result<Vec<char>, String> {
+pub fn conditional_reader(consumer: &mut dyn Consumer, predicate: fn(&char) -> bool) -> Result<Vec<char>, LexError> {
 	let mut chars: Vec<char> = vec![];
 	loop {
 		let next = consumer.peek().unwrap_or('\0');
@@ -59,26 +135,31 @@ pub fn conditional_reader(consumer: &mut dyn Consumer, predicate: fn(&char) -> b
 	Ok(chars)
 }
 
-pub fn read_number(consumer: &mut dyn Consumer) -> Result<Vec<char>, String> {
+pub fn read_number(consumer: &mut dyn Consumer) -> Result<Vec<char>, LexError> {
 	conditional_reader(consumer, is_number)
 }
 
-pub fn read_nomenclature(consumer: &mut dyn Consumer) -> Result<Vec<char>, String> {
+pub fn read_nomenclature(consumer: &mut dyn Consumer) -> Result<Vec<char>, LexError> {
 	conditional_reader(consumer, |c| is_nomenclature(c, false))
 }
 
-pub fn read_string(consumer: &mut dyn Consumer) -> Result<Vec<char>, String> {
+pub fn read_string(consumer: &mut dyn Consumer) -> Result<Vec<char>, LexError> {
 	let mut chars: Vec<char> = vec![];
+	let mut invalid_escape = false;
 	// Ignore the first character which is a '"'
 	consumer.consume_and_ignore()?;
 	loop {
-		let next = consumer.consume()?;
+		let next = consumer.consume().map_err(|_| LexError::UnterminatedString)?;
 		if next == '"' {
+			// Invalid escapes are reported once the whole literal has been read so the consumer is left after it
+			if invalid_escape {
+				return Err(LexError::InvalidEscape);
+			}
 			return Ok(chars);
 		} else if next == '\\' {
 			match consumer.peek().unwrap_or('\0') {
 				'\\' | '"' => chars.push(consumer.consume()?),
-				_ => return Err("Unexpected escape character".to_string()),
+				_ => invalid_escape = true,
 			}
 		} else {
 			chars.push(next);
@@ -86,30 +167,53 @@ pub fn read_string(consumer: &mut dyn Consumer) -> Result<Vec<char>, String> {
 	}
 }
 
-fn read_delimited_block(consumer: &mut dyn Consumer, delimiter: char, ttype: TokenType) -> Result<Vec<char>, String> {
+/**
+Reads a block between two lines of triple `delimiter`s. When `dedent` is set, the indentation that every line of the
+block shares is removed, unless the opening delimiter is followed by `verbatim`.
+*/
+fn read_delimited_block(
+	consumer: &mut dyn Consumer,
+	delimiter: char,
+	ttype: TokenType,
+	dedent: bool,
+) -> Result<Vec<char>, LexError> {
+	read_block(consumer, delimiter, ttype, dedent, false).map(|(_, block)| block)
+}
+
+/** Like [`read_delimited_block`], also returning the rest of the opening line when `header` allows one. */
+fn read_block(
+	consumer: &mut dyn Consumer,
+	delimiter: char,
+	ttype: TokenType,
+	dedent: bool,
+	header: bool,
+) -> Result<(String, Vec<char>), LexError> {
 	consumer.consume_and_ignore()?;
 	consumer.consume_and_ignore()?;
 	consumer.consume_and_ignore()?;
 	// ignore whitespace until we reach an EOL
-	if let Err(err) = scan_whitespace(consumer) {
-		return Err(err.to_string());
-	}
+	scan_whitespace(consumer)?;
+	let verbatim = dedent && read_keyword(consumer, VERBATIM)?;
+	let mut trailing: Option<LexError> = None;
+	let mut rest = String::new();
 	match consumer.peek() {
 		Some(next) => {
-			if next != '\n' {
-				return Err(format!(
-					"Did not find EOL. {ttype:?} block symbols must not be followed by any other content"
-				));
+			if header {
+				rest = conditional_reader(consumer, |x| *x != '\n' && *x != '\0')?.iter().collect();
+			} else if next != '\n' {
+				trailing = Some(LexError::TrailingContent(ttype.clone()));
+				// Keep reading the block so the consumer is left after its closing delimiter
+				conditional_reader(consumer, |x| *x != '\n' && *x != '\0')?;
 			}
 			consumer.consume_and_ignore()?;
 		}
-		None => return Err(format!("Unexpected EOF when expecting {ttype:?} block text")),
+		None => return Err(LexError::MissingBlockText(ttype)),
 	}
 
 	let mut result: Vec<char> = vec![];
 	let mut current: char;
 	loop {
-		current = consumer.consume()?;
+		current = consumer.consume().map_err(|_| LexError::UnterminatedBlock(ttype.clone()))?;
 		if current == delimiter {
 			let la2 = consumer.look_ahead(1);
 			let la3 = consumer.look_ahead(2);
@@ -121,35 +225,102 @@ fn read_delimited_block(consumer: &mut dyn Consumer, delimiter: char, ttype: Tok
 		}
 		result.push(current);
 	}
+	if let Some(error) = trailing {
+		return Err(error);
+	}
 	// Remove the last newline character
 	if let Some(index) = result.iter().rposition(|x| *x == '\n') {
 		result.remove(index);
 	}
-	Ok(result)
+	if dedent && !verbatim {
+		result = dedent_block(&result).ok_or(LexError::MixedIndentation(ttype))?;
+	}
+	Ok((rest.trim().to_string(), result))
+}
+
+/** Keeps the indentation of a script block as written, ie. `%%% verbatim` */
+const VERBATIM: &str = "verbatim";
+
+/** Consumes `keyword` and the whitespace after it if it is the next word on the line. */
+fn read_keyword(consumer: &mut dyn Consumer, keyword: &str) -> Result<bool, LexError> {
+	let matches = keyword.chars().enumerate().all(|(index, c)| consumer.look_ahead(index + 1) == Some(c));
+	let ends = consumer.look_ahead(keyword.len() + 1).is_none_or(|next| next.is_whitespace());
+	if !matches || !ends {
+		return Ok(false);
+	}
+	for _ in 0..keyword.len() {
+		consumer.consume_and_ignore()?;
+	}
+	scan_whitespace(consumer)?;
+	Ok(true)
+}
+
+/**
+Removes the indentation that every non-blank line shares, and empties the blank lines. Returns `None` if the lines
+disagree on whether that indentation is tabs or spaces.
+*/
+fn dedent_block(block: &[char]) -> Option<Vec<char>> {
+	let text: String = block.iter().collect();
+	let lines: Vec<&str> = text.split('\n').collect();
+	let blank = |line: &&str| line.trim().is_empty();
+	let indents: Vec<&str> = lines
+		.iter()
+		.filter(|line| !blank(line))
+		.map(|line| &line[..line.len() - line.trim_start_matches([' ', '\t']).len()])
+		.collect();
+	let shortest = indents.iter().map(|indent| indent.len()).min().unwrap_or(0);
+	if indents.iter().any(|indent| indent[..shortest] != indents[0][..shortest]) {
+		return None;
+	}
+	let lines: Vec<&str> = lines.iter().map(|line| if blank(line) { "" } else { &line[shortest..] }).collect();
+	Some(lines.join("\n").chars().collect())
 }
 
-pub fn read_comment(consumer: &mut dyn Consumer) -> Result<Vec<char>, String> {
+pub fn read_comment(consumer: &mut dyn Consumer) -> Result<Vec<char>, LexError> {
 	if consumer.look_ahead(1).unwrap_or('\0') == '#' && consumer.look_ahead(2).unwrap_or('\0') == '#' {
-		return read_delimited_block(consumer, '#', TokenType::SCR_SH);
+		return read_delimited_block(consumer, '#', TokenType::COMMENT, false);
 	}
 	// ignore the first '#' character
 	consumer.consume_and_ignore()?;
 	conditional_reader(consumer, |x| *x != '\n')
 }
 
-pub fn read_script(consumer: &mut dyn Consumer, delimiter: char) -> Result<Vec<char>, String> {
+/** Reads a `$`, `%` or `!!!` script, along with the kind of program that runs it. */
+pub fn read_script(consumer: &mut dyn Consumer, delimiter: char) -> Result<(ScriptKind, Vec<char>), LexError> {
+	let kind = match delimiter {
+		'%' => ScriptKind::Python,
+		'!' => ScriptKind::Program(String::new()),
+		_ => ScriptKind::Shell,
+	};
+	if delimiter == '!' && consumer.look_ahead(3) != Some('!') {
+		// Skip the rest of the line, so that the lexer carries on from the next one
+		conditional_reader(consumer, |x| *x != '\n' && *x != '\0')?;
+		return Err(LexError::UnknownProgramScript);
+	}
 	if consumer.look_ahead(1).unwrap_or('\0') == delimiter && consumer.look_ahead(2).unwrap_or('\0') == delimiter {
-		return read_delimited_block(consumer, delimiter, TokenType::SCR_SH);
+		let ttype = TokenType::SCRIPT(kind.clone());
+		let (program, script) = read_block(consumer, delimiter, ttype, true, delimiter == '!')?;
+		return match (kind, shebang(&script)) {
+			(ScriptKind::Program(_), _) if !program.is_empty() => Ok((ScriptKind::Program(program), script)),
+			(ScriptKind::Program(_) | ScriptKind::Shell, Some(program)) => Ok((ScriptKind::Program(program), script)),
+			(ScriptKind::Program(_), None) => Err(LexError::MissingProgram),
+			(kind, _) => Ok((kind, script)),
+		};
 	}
 	// ignore the first '$' character
 	consumer.consume_and_ignore()?;
 	scan_whitespace(consumer)?;
-	conditional_reader(consumer, |x| *x != '\n' && *x != '\0')
+	Ok((kind, conditional_reader(consumer, |x| *x != '\n' && *x != '\0')?))
+}
+
+/** The command of the `#!` line that a block starts with, which stays in the script for the program to skip. */
+fn shebang(script: &[char]) -> Option<String> {
+	let line: String = script.iter().skip_while(|c| **c == '\n').take_while(|c| **c != '\n').collect();
+	line.strip_prefix("#!").map(str::trim).filter(|program| !program.is_empty()).map(str::to_string)
 }
 
-pub fn convert_help_block_escapes(parsed: Result<Vec<char>, String>) -> Result<Vec<char>, String> {
-	parsed.as_ref()?;
-	let text = parsed.unwrap();
+pub fn convert_help_block_escapes(parsed: Result<Vec<char>, LexError>) -> Result<Vec<char>, LexError> {
+	let text = parsed?;
 	let mut cons = VecCharConsumer::new(&text);
 	let mut result: Vec<char> = Vec::new();
 	while let Ok(mut next) = cons.consume() {
@@ -171,9 +342,9 @@ pub fn convert_help_block_escapes(parsed: Result<Vec<char>, String>) -> Result<V
 	}
 	Ok(result)
 }
-pub fn read_help_block(consumer: &mut dyn Consumer) -> Result<Vec<char>, String> {
+pub fn read_help_block(consumer: &mut dyn Consumer) -> Result<Vec<char>, LexError> {
 	if consumer.look_ahead(1).unwrap_or('\0') == '@' && consumer.look_ahead(2).unwrap_or('\0') == '@' {
-		return convert_help_block_escapes(read_delimited_block(consumer, '@', TokenType::HELP));
+		return convert_help_block_escapes(read_delimited_block(consumer, '@', TokenType::HELP, false));
 	}
 	// ignore the first '@' character
 	consumer.consume_and_ignore()?;
@@ -185,13 +356,19 @@ pub fn read_help_block(consumer: &mut dyn Consumer) -> Result<Vec<char>, String>
 mod tests {
 	use crate::lexer::lexers::{read_comment, read_help_block, read_nomenclature, read_script, read_string, convert_help_block_escapes};
 
-	use super::{read_number, scan_whitespace, Consumer};
+	use crate::lexer::token::{ScriptKind, TokenType};
 
-	fn read_script_sh(consumer: &mut dyn Consumer) -> Result<Vec<char>, String> {
-		read_script(consumer, '$')
+	use super::{read_number, scan_whitespace, Consumer, LexError};
+
+	fn read_script_sh(consumer: &mut dyn Consumer) -> Result<Vec<char>, LexError> {
+		read_script(consumer, '$').map(|(_, script)| script)
+	}
+	fn read_script_py(consumer: &mut dyn Consumer) -> Result<Vec<char>, LexError> {
+		read_script(consumer, '%').map(|(_, script)| script)
 	}
-	fn read_script_py(consumer: &mut dyn Consumer) -> Result<Vec<char>, String> {
-		read_script(consumer, '%')
+	fn read_script_kind(source: &str) -> Result<ScriptKind, LexError> {
+		let mut consumer = MockConsumer::new(source);
+		read_script(&mut consumer, source.chars().next().unwrap()).map(|(kind, _)| kind)
 	}
 
 	struct MockConsumer {
@@ -204,9 +381,9 @@ mod tests {
 		}
 	}
 	impl Consumer for MockConsumer {
-		fn consume(&mut self) -> Result<char, String> {
+		fn consume(&mut self) -> Result<char, LexError> {
 			if self.index >= self.source.len() {
-				return Err("End of file".to_string());
+				return Err(LexError::EndOfFile);
 			}
 			let value = *self.source.get(self.index).unwrap();
 			self.index += 1;
@@ -215,7 +392,7 @@ mod tests {
 			}
 			Ok(value)
 		}
-		fn consume_and_ignore(&mut self) -> Result<(), String> {
+		fn consume_and_ignore(&mut self) -> Result<(), LexError> {
 			let _ = self.consume()?;
 			Ok(())
 		}
@@ -236,7 +413,7 @@ mod tests {
 		}
 	}
 
-	fn run_test(target: fn(&mut dyn Consumer) -> Result<Vec<char>, String>, test_data: &str) -> String {
+	fn run_test(target: fn(&mut dyn Consumer) -> Result<Vec<char>, LexError>, test_data: &str) -> String {
 		let mut consumer = MockConsumer::new(test_data);
 		res(target(&mut consumer)).iter().collect()
 	}
@@ -296,17 +473,47 @@ mod tests {
 			"echo 1234 | cat\necho hello world",
 		);
 	}
+	#[test]
 	fn test_read_script_py() {
-		assert_eq!(run_test(read_script_sh, "% print(\"hello world\")  \n"), "print(\"hello world\")  ");
+		assert_eq!(run_test(read_script_py, "% print(\"hello world\")  \n"), "print(\"hello world\")  ");
 		assert_eq!(
 			run_test(
-				read_script_sh,
+				read_script_py,
 				"%%%\noutput='1234'\nprint(output)\n%%%\n nothing here   "
 			),
 			"output='1234'\nprint(output)",
 		);
 	}
 	#[test]
+	fn test_read_script_dedent() {
+		let block = "$$$\n\tif true; then\n\t\techo yes\n  \n\tfi\n$$$\n";
+		assert_eq!(run_test(read_script_sh, block), "if true; then\n\techo yes\n\nfi");
+		assert_eq!(run_test(read_script_py, "%%%\n\t  x = 1\n\t  print(x)\n%%%\n"), "x = 1\nprint(x)");
+		assert_eq!(run_test(read_script_py, "%%% verbatim\n\tx = 1\n%%%\n"), "\tx = 1");
+		let mut consumer = MockConsumer::new("%%%\n\tx = 1\n    print(x)\n%%%\n");
+		let mixed = LexError::MixedIndentation(TokenType::SCRIPT(ScriptKind::Python));
+		assert_eq!(read_script_py(&mut consumer), Err(mixed));
+		let mut consumer = MockConsumer::new("$$$ verbatims\n\tls\n$$$\n");
+		let trailing = LexError::TrailingContent(TokenType::SCRIPT(ScriptKind::Shell));
+		assert_eq!(read_script_sh(&mut consumer), Err(trailing));
+	}
+	#[test]
+	fn test_read_script_program() {
+		let program = |name: &str| Ok(ScriptKind::Program(name.to_string()));
+		assert_eq!(read_script_kind("!!! node \n\tconsole.log(1)\n!!!\n"), program("node"));
+		assert_eq!(read_script_kind("!!! verbatim deno run\n\tconsole.log(1)\n!!!\n"), program("deno run"));
+		assert_eq!(read_script_kind("!!!\n\t#!/usr/bin/env ruby\n\tputs 1\n!!!\n"), program("/usr/bin/env ruby"));
+		assert_eq!(read_script_kind("$$$\n#!/bin/bash -e\nls\n$$$\n"), program("/bin/bash -e"));
+		assert_eq!(read_script_kind("$$$\n# ls\nls\n$$$\n"), Ok(ScriptKind::Shell));
+		assert_eq!(read_script_kind("%%%\n#!/usr/bin/env python3\n%%%\n"), Ok(ScriptKind::Python));
+		assert_eq!(read_script_kind("!!!\n\tputs 1\n!!!\n"), Err(LexError::MissingProgram));
+		let mut consumer = MockConsumer::new("!!node -e 1\n$ ls\n");
+		assert_eq!(read_script(&mut consumer, '!'), Err(LexError::UnknownProgramScript));
+		assert_eq!(consumer.peek(), Some('\n'));
+		let mut consumer = MockConsumer::new("!!! bash\n\t#!/bin/sh\n\techo $0\n!!!\n");
+		assert_eq!(res(read_script(&mut consumer, '!')).1.iter().collect::<String>(), "#!/bin/sh\necho $0");
+	}
+	#[test]
 	fn test_read_help_block() {
 		assert_eq!(run_test(read_help_block, "@ everything to the end   \n"), "everything to the end   ");
 		assert_eq!(run_test(read_help_block, "@@@\n Everything in here\n@@@ nothing here   "), " Everything in here");
@@ -1,18 +1,15 @@
-// #![allow(dead_code)]
-
-use std::{
-	cmp::max,
-	io::{Error, ErrorKind},
-};
-
 mod validation;
 use validation::{is_nomenclature, is_number};
 pub mod token;
-use token::{Token, TokenType, Tokenizer};
+use token::{Span, Token, TokenType, Tokenizer};
 
-use crate::lexer::lexers::read_comment;
+use crate::{
+	diagnostics::Diagnostic,
+	error::DoitError,
+	lexer::lexers::read_comment,
+};
 
-use self::lexers::{read_nomenclature, read_number, read_script, read_string, scan_whitespace, Consumer};
+use self::lexers::{read_nomenclature, read_number, read_script, read_string, scan_whitespace, Consumer, LexError};
 mod lexers;
 
 pub struct Lexer<'lexer> {
@@ -21,14 +18,19 @@ pub struct Lexer<'lexer> {
 	index: usize,
 	row: usize,
 	col: i32,
+	/** Line and column of the most recently consumed character, used to close token spans. */
+	last_row: usize,
+	last_col: usize,
+	/** Index, line and column at which the token currently being read starts. */
+	token_start: (usize, usize, usize),
 	peeked_token: Option<Token>,
 	first: bool,
 }
 
 impl Consumer for Lexer<'_> {
-	fn consume(&mut self) -> Result<char, String> {
+	fn consume(&mut self) -> Result<char, LexError> {
 		if self.index >= self.source.len() {
-			return Err("End of file".to_string());
+			return Err(LexError::EndOfFile);
 		}
 		let value = *self.source.get(self.index).unwrap();
 		self.index += 1;
@@ -36,13 +38,15 @@ impl Consumer for Lexer<'_> {
 		if value == '\r' {
 			return self.consume();
 		}
+		self.last_row = self.row;
+		self.last_col = self.col as usize;
 		if value == '\n' {
 			self.col = 0;
 			self.row += 1;
 		}
 		Ok(value)
 	}
-	fn consume_and_ignore(&mut self) -> Result<(), String> {
+	fn consume_and_ignore(&mut self) -> Result<(), LexError> {
 		let _ = self.consume()?;
 		Ok(())
 	}
@@ -65,65 +69,82 @@ impl Lexer<'_> {
 			source: source.chars().collect(),
 			row: 1,
 			col: 0,
+			last_row: 1,
+			last_col: 0,
+			token_start: (0, 1, 1),
 			peeked_token: None,
 			first: true,
 		}
 	}
 
-	fn consume_and_ignore(&mut self) -> Result<(), Error> {
+	fn consume_and_ignore(&mut self) -> Result<(), DoitError> {
 		match self.consume() {
 			Ok(_) => Ok(()),
-			Err(err) => Err(Error::other(err)),
+			Err(err) => self.generate_error(err),
 		}
 	}
 
-	fn generate_error<T>(&self, error_kind: ErrorKind, message: &str) -> Result<T, Error> {
-		Err(Error::new(
-			error_kind,
-			format!("{}:{}:{} > {message}", self.filename, self.row, self.col),
-		))
-	}
-
-	fn peek_next(&self) -> Option<char> {
-		if self.index >= self.source.len() {
-			return None;
+	/** Span from the start of the current token up to the last consumed character. */
+	fn current_span(&self) -> Span {
+		let (index, row, col) = self.token_start;
+		if self.index <= index {
+			return Span::new(row, col, row, col);
 		}
-		Some(self.source[self.index + 1])
+		Span::new(row, col, self.last_row, self.last_col)
 	}
 
-	fn put_back(&mut self) {
-		self.index = max(0, self.index - 1);
-		if self.source.get(self.index) == Some(&'\n') {
-			self.row -= 1;
-		}
+	fn generate_error<T>(&self, error: LexError) -> Result<T, DoitError> {
+		Err(DoitError::Lex(vec![Diagnostic::error(error.code(), &error.to_string(), self.current_span())]))
 	}
 
-	fn handle_error(&self, read: Result<Vec<char>, String>) -> Result<String, Error> {
+	fn handle_error(&self, read: Result<Vec<char>, LexError>) -> Result<String, DoitError> {
 		match read {
 			Ok(v) => Ok(v.iter().collect()),
-			Err(e) => self.generate_error(ErrorKind::InvalidData, &e),
+			Err(e) => self.generate_error(e),
 		}
 	}
 
-	fn consume_argdef(&mut self, ttype: TokenType) -> Result<Token, Error> {
+	fn consume_argdef(&mut self, ttype: TokenType) -> Result<Token, DoitError> {
 		if let Err(err) = scan_whitespace(self) {
-			return self.generate_error(ErrorKind::InvalidData, &err);
+			return self.generate_error(err);
 		}
 		let symbol: String = match read_nomenclature(self) {
 			Ok(v) => v.iter().collect(),
-			Err(e) => return self.generate_error(ErrorKind::InvalidData, &e),
+			Err(e) => return self.generate_error(e),
 		};
 		Ok(Token::val(ttype, Some(symbol)))
 	}
 
-	fn consume_token(&mut self) -> Result<Token, Error> {
+	fn consume_token(&mut self) -> Result<Token, DoitError> {
 		if self.first {
 			self.first = false;
-			return Ok(Token::sym(TokenType::SOF));
+			return Ok(Token::sym(TokenType::SOF).with_span(Span::new(1, 1, 1, 1)));
 		}
 		if let Err(err) = scan_whitespace(self) {
-			return self.generate_error(ErrorKind::InvalidData, &err);
+			return self.generate_error(err);
 		}
+		let (start_index, start_row, start_col) = (self.index, self.row, self.col as usize + 1);
+		self.token_start = (start_index, start_row, start_col);
+		let token = match self.read_token() {
+			Ok(token) => token,
+			Err(error) => {
+				// Always make progress so a caller that recovers from the error does not read the same one forever
+				if self.index == start_index {
+					let _ = Consumer::consume(self);
+				}
+				return Err(error);
+			}
+		};
+		let span = if self.index == start_index {
+			// Zero width tokens (EOF) start and end at the same position
+			Span::new(start_row, start_col, start_row, start_col)
+		} else {
+			Span::new(start_row, start_col, self.last_row, self.last_col)
+		};
+		Ok(token.with_span(span))
+	}
+
+	fn read_token(&mut self) -> Result<Token, DoitError> {
 		let next = match self.peek() {
 			Some(v) => v,
 			None => return Ok(Token::sym(TokenType::EOF)),
@@ -131,12 +152,12 @@ impl Lexer<'_> {
 		if is_number(&next) {
 			match read_number(self) {
 				Ok(v) => Ok(Token::val(TokenType::LIT_NUM, Some(v.iter().collect()))),
-				Err(e) => self.generate_error(ErrorKind::InvalidData, &e),
+				Err(e) => self.generate_error(e),
 			}
 		} else if is_nomenclature(&next, true) {
 			let symbol: String = match read_nomenclature(self) {
 				Ok(v) => v.iter().collect(),
-				Err(e) => return self.generate_error(ErrorKind::InvalidData, &e),
+				Err(e) => return self.generate_error(e),
 			};
 			match symbol.as_str() {
 				"exit" => Ok(Token::sym(TokenType::EXIT)),
@@ -153,14 +174,14 @@ impl Lexer<'_> {
 			let result = read_comment(self);
 			let value = self.handle_error(result)?;
 			Ok(Token::val(TokenType::COMMENT, Some(value)))
-		} else if next == '$' {
-			let result = read_script(self, '$');
-			let value = self.handle_error(result)?.trim().to_string();
-			Ok(Token::val(TokenType::SCR_SH, Some(value)))
-		} else if next == '%' {
-			let result = read_script(self, '%');
-			let value = self.handle_error(result)?.trim().to_string();
-			Ok(Token::val(TokenType::SCR_PY, Some(value)))
+		} else if next == '$' || next == '%' || (next == '!' && self.look_ahead(2) == Some('!')) {
+			let (kind, script) = match read_script(self, next) {
+				Ok(v) => v,
+				Err(e) => return self.generate_error(e),
+			};
+			// Blocks are already dedented, and a verbatim block keeps the indentation of its first line
+			let value = script.iter().collect::<String>().trim_start_matches('\n').trim_end().to_string();
+			Ok(Token::val(TokenType::SCRIPT(kind), Some(value)))
 		} else if next == '{' {
 			self.consume_and_ignore()?;
 			Ok(Token::sym(TokenType::TGT_BEG))
@@ -195,14 +216,14 @@ impl Tokenizer for Lexer<'_> {
 	fn get_charno(&self) -> i32 {
 		self.col
 	}
-	fn peek_token(&mut self) -> Result<&Token, Error> {
+	fn peek_token(&mut self) -> Result<&Token, DoitError> {
 		if self.peeked_token.is_none() {
 			self.peeked_token = Some(self.consume_token()?);
 		}
 		Ok(self.peeked_token.as_ref().unwrap())
 	}
 
-	fn next_token(&mut self) -> Result<Token, Error> {
+	fn next_token(&mut self) -> Result<Token, DoitError> {
 		let token = self.peeked_token.take();
 		match token {
 			Some(t) => Ok(t),
@@ -213,21 +234,22 @@ impl Tokenizer for Lexer<'_> {
 
 #[cfg(test)]
 mod tests {
-	use crate::lexer::token::TokenType;
+	use crate::{
+		error::DoitError,
+		lexer::token::{ScriptKind, TokenType},
+	};
 
 	use super::{
-		token::{Token, Tokenizer},
+		token::{Span, Token, Tokenizer},
 		Lexer,
 	};
-	use std::io::Error;
-
 	fn check(token: Token, expected_type: TokenType, expected_value: &str) {
 		assert_eq!(token.ttype, expected_type);
 		assert_eq!(token.value.unwrap_or("".to_string()).as_str(), expected_value);
 	}
 
 	#[test]
-	fn test_lexer() -> Result<(), Error> {
+	fn test_lexer() -> Result<(), DoitError> {
 		let mut lexer = Lexer::new(
 			"test-source.it",
 			r"@@@
@@ -278,9 +300,9 @@ test3:% python3
 		check(lexer.next_token()?, TokenType::ARG_OPT, "-d");
 		check(lexer.next_token()?, TokenType::HELP, "help4");
 		check(lexer.next_token()?, TokenType::EOL, "");
-		check(lexer.next_token()?, TokenType::SCR_SH, "script1");
+		check(lexer.next_token()?, TokenType::SCRIPT(ScriptKind::Shell), "script1");
 		check(lexer.next_token()?, TokenType::EOL, "");
-		check(lexer.next_token()?, TokenType::SCR_SH, "script2");
+		check(lexer.next_token()?, TokenType::SCRIPT(ScriptKind::Shell), "script2");
 		check(lexer.next_token()?, TokenType::EOL, "");
 		check(lexer.next_token()?, TokenType::YIELD, "");
 		check(lexer.next_token()?, TokenType::EOL, "");
@@ -288,21 +310,67 @@ test3:% python3
 		check(lexer.next_token()?, TokenType::EOL, "");
 		check(lexer.next_token()?, TokenType::COMMENT, " comment2");
 		check(lexer.next_token()?, TokenType::EOL, "");
-		check(lexer.next_token()?, TokenType::SCR_PY, "python1");
+		check(lexer.next_token()?, TokenType::SCRIPT(ScriptKind::Python), "python1");
 		check(lexer.next_token()?, TokenType::EOL, "");
-		check(lexer.next_token()?, TokenType::SCR_PY, "python2");
+		check(lexer.next_token()?, TokenType::SCRIPT(ScriptKind::Python), "python2");
 		check(lexer.next_token()?, TokenType::EOL, "");
 		check(lexer.next_token()?, TokenType::TGT_END, "");
 		check(lexer.next_token()?, TokenType::EOL, "");
 		check(lexer.next_token()?, TokenType::NOMEN, "test2");
 		check(lexer.next_token()?, TokenType::TGT_SLE, "");
-		check(lexer.next_token()?, TokenType::SCR_SH, "script3");
+		check(lexer.next_token()?, TokenType::SCRIPT(ScriptKind::Shell), "script3");
 		check(lexer.next_token()?, TokenType::EOL, "");
 		check(lexer.next_token()?, TokenType::NOMEN, "test3");
 		check(lexer.next_token()?, TokenType::TGT_SLE, "");
-		check(lexer.next_token()?, TokenType::SCR_PY, "python3");
+		check(lexer.next_token()?, TokenType::SCRIPT(ScriptKind::Python), "python3");
 		check(lexer.next_token()?, TokenType::EOL, "");
 		check(lexer.next_token()?, TokenType::EOF, "");
 		Ok(())
 	}
+
+	#[test]
+	fn test_lexer_spans() -> Result<(), DoitError> {
+		let mut lexer = Lexer::new("test-source.it", "name = \"abc\"\ntgt {\n\t$$$\n\tls\n\t$$$\n}\n");
+		assert_eq!(lexer.next_token()?.span, Span::new(1, 1, 1, 1));
+		assert_eq!(lexer.next_token()?.span, Span::new(1, 1, 1, 4));
+		assert_eq!(lexer.next_token()?.span, Span::new(1, 6, 1, 6));
+		assert_eq!(lexer.next_token()?.span, Span::new(1, 8, 1, 12));
+		assert_eq!(lexer.next_token()?.span, Span::new(1, 13, 1, 13));
+		assert_eq!(lexer.next_token()?.span, Span::new(2, 1, 2, 3));
+		assert_eq!(lexer.next_token()?.span, Span::new(2, 5, 2, 5));
+		assert_eq!(lexer.next_token()?.span, Span::new(2, 6, 2, 6));
+		let script = lexer.next_token()?;
+		assert_eq!(script.ttype, TokenType::SCRIPT(ScriptKind::Shell));
+		assert_eq!(script.span, Span::new(3, 2, 5, 4));
+		assert_eq!(lexer.next_token()?.span, Span::new(5, 5, 5, 5));
+		assert_eq!(lexer.next_token()?.span, Span::new(6, 1, 6, 1));
+		assert_eq!(lexer.next_token()?.span, Span::new(6, 2, 6, 2));
+		let eof = lexer.next_token()?;
+		assert_eq!(eof.ttype, TokenType::EOF);
+		assert_eq!(eof.span, Span::new(7, 1, 7, 1));
+		Ok(())
+	}
+
+	#[test]
+	fn test_lexer_diagnostics() -> Result<(), DoitError> {
+		let mut lexer = Lexer::new("test-source.it", "a = \"x\\q\"\nb {\n\t$$$ ls\n}\n");
+		lexer.next_token()?;
+		lexer.next_token()?;
+		lexer.next_token()?;
+		let error = lexer.next_token().err().unwrap();
+		assert!(matches!(error, DoitError::Lex(_)));
+		let diagnostic = &error.diagnostics()[0];
+		assert_eq!(diagnostic.code, Some("E002"));
+		assert_eq!(diagnostic.span, Span::new(1, 5, 1, 9));
+		let mut lexer = Lexer::new("test-source.it", "b {\n\t$$$ ls\n}\n");
+		for _ in 0..4 {
+			lexer.next_token()?;
+		}
+		let error = lexer.next_token().err().unwrap();
+		let diagnostic = &error.diagnostics()[0];
+		assert_eq!(diagnostic.code, Some("E003"));
+		assert_eq!(diagnostic.span.start_line, 2);
+		assert_eq!(diagnostic.span.start_col, 2);
+		Ok(())
+	}
 }
@@ -1,5 +1,6 @@
 use core::fmt;
-use std::io::Error;
+
+use crate::error::DoitError;
 
 #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone)]
@@ -13,8 +14,7 @@ pub enum TokenType {
 	LIT_NUM, // 42
 	LIT_STR, // "abc"
 	COMMENT, // #
-	SCR_SH,  // $ $$$
-	SCR_PY,  // % %%%
+	SCRIPT(ScriptKind), // $ $$$ % %%% !!!
 	TGT_BEG, // {
 	TGT_END, // }
 	TGT_SLE, // :
@@ -25,33 +25,99 @@ pub enum TokenType {
 	ARG_OPT, // opt
 }
 
+/** The program that runs a script */
+#[derive(Debug, PartialEq, Clone)]
+pub enum ScriptKind {
+	/** `$` and `$$$`, run by `/bin/sh -c` or `DOIT_SHELL` */
+	Shell,
+	/** `%` and `%%%`, run by `python3 -c` or `DOIT_PYTHON` */
+	Python,
+	/** `!!! node` blocks and blocks that start with a `#!` line, saved to a file that the command runs */
+	Program(String),
+}
+
+impl ScriptKind {
+	/** Printed before the script by dry runs and traces */
+	pub fn symbol(&self) -> char {
+		match self {
+			ScriptKind::Shell => '$',
+			ScriptKind::Python => '%',
+			ScriptKind::Program(_) => '!',
+		}
+	}
+}
+
 pub trait Tokenizer {
-	fn peek_token(&mut self) -> Result<&Token, Error>;
-	fn next_token(&mut self) -> Result<Token, Error>;
+	fn peek_token(&mut self) -> Result<&Token, DoitError>;
+	fn next_token(&mut self) -> Result<Token, DoitError>;
 	fn get_filename(&self) -> &str;
 	fn get_lineno(&self) -> usize;
 	fn get_charno(&self) -> i32;
 }
 
+/** Inclusive range of 1-based line/column positions in the do.it source. Line 0 means "unknown". */
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span {
+	pub start_line: usize,
+	pub start_col: usize,
+	pub end_line: usize,
+	pub end_col: usize,
+}
+
+impl Span {
+	pub const EMPTY: Span = Span { start_line: 0, start_col: 0, end_line: 0, end_col: 0 };
+
+	pub const fn new(start_line: usize, start_col: usize, end_line: usize, end_col: usize) -> Span {
+		Span { start_line, start_col, end_line, end_col }
+	}
+	pub const fn is_empty(&self) -> bool {
+		self.start_line == 0
+	}
+	/** Returns the smallest span that covers both `self` and `other`, ignoring empty spans. */
+	pub fn to(&self, other: &Span) -> Span {
+		if self.is_empty() {
+			return *other;
+		}
+		if other.is_empty() {
+			return *self;
+		}
+		let (start_line, start_col) = (self.start_line, self.start_col).min((other.start_line, other.start_col));
+		let (end_line, end_col) = (self.end_line, self.end_col).max((other.end_line, other.end_col));
+		Span { start_line, start_col, end_line, end_col }
+	}
+}
+
+impl fmt::Display for Span {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}:{}-{}:{}", self.start_line, self.start_col, self.end_line, self.end_col)
+	}
+}
+
+#[derive(Clone)]
 pub struct Token {
 	pub ttype: TokenType,
 	pub value: Option<String>,
+	pub span: Span,
 }
 
 impl Token {
 	pub const fn sym(ttype: TokenType) -> Token {
-		Token { ttype, value: None }
+		Token { ttype, value: None, span: Span::EMPTY }
 	}
 	pub const fn val(ttype: TokenType, value: Option<String>) -> Token {
-		Token { ttype, value }
+		Token { ttype, value, span: Span::EMPTY }
+	}
+	pub const fn with_span(mut self, span: Span) -> Token {
+		self.span = span;
+		self
 	}
 }
 
 impl fmt::Display for Token {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match &self.value {
-			Some(v) => write!(f, "{:?}({})", self.ttype, v),
-			None => write!(f, "{:?}", self.ttype),
+			Some(v) => write!(f, "{:?}({}) [{}]", self.ttype, v, self.span),
+			None => write!(f, "{:?} [{}]", self.ttype, self.span),
 		}
 	}
 }
@@ -1,7 +1,3 @@
-pub fn is_whitespace(value: &char) -> bool {
-	value.is_whitespace()
-}
-
 pub fn is_number(value: &char) -> bool {
 	value.is_numeric() || *value == '.'
 }
@@ -14,12 +10,7 @@ pub fn is_nomenclature(value: &char, first: bool) -> bool {
 #[cfg(test)]
 mod tests {
 	use crate::lexer::validation::is_number;
-	use super::{is_nomenclature, is_whitespace};
-	#[test]
-	fn test_is_whitespace() {
-		" \n\t\r".chars().for_each(|c|assert!(is_whitespace(&c)));
-		"abcdefghijklmnopqrstuvwxyz!@#$%^&*()-=`~'\":;/\\,<>+_".chars().for_each(|c|assert!(!is_whitespace(&c)));
-	}
+	use super::is_nomenclature;
 	#[test]
 	fn test_is_number() {
 		"0123456789.".chars().for_each(|c|assert!(is_number(&c)));
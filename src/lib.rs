@@ -0,0 +1,20 @@
+/*!
+Transpiles do.it files to C++ and runs their targets. The [`program::Program`] model is shared by every backend, and
+new ones can be added by implementing [`backend::Backend`].
+*/
+
+pub mod backend;
+pub mod cache;
+pub mod compiler;
+pub mod diagnostics;
+pub mod error;
+pub mod events;
+pub mod generator;
+pub mod interpreter;
+pub mod lexer;
+pub mod options;
+pub mod parser;
+pub mod program;
+pub mod signals;
+pub mod toolchain;
+pub mod utils;
@@ -1,19 +1,33 @@
-#![allow(dead_code)]
 use std::{
-	env, fs,
-	path::Path,
+	env, fs, io,
+	os::unix::process::{CommandExt, ExitStatusExt},
+	path::{Path, PathBuf},
 	process::{Command, ExitCode, exit},
 };
 
-mod compiler;
-mod generator;
-mod lexer;
-pub mod parser;
-mod utils;
+use doit::{
+	backend, cache,
+	compiler::{self, CompileMode},
+	diagnostics,
+	error::DoitError,
+	events::{self, Event, EventKind},
+	interpreter,
+	options::RunOptions,
+	signals, toolchain,
+	utils::log,
+};
 
-use compiler::CompileMode;
+/** How the targets of the do.it file are run */
+#[derive(PartialEq, Clone, Copy)]
+enum Backend {
+	/** Compile to C++, falling back to the interpreter when no C++ compiler is available */
+	Auto,
+	Cpp,
+	Interp,
+}
 
-use crate::utils::log;
+/** Number of days a cache may go unused before `--gc` removes it */
+const DEFAULT_GC_DAYS: u64 = 30;
 
 fn print_help(program_name: &str) {
 	println!(
@@ -25,13 +39,33 @@ fn print_help(program_name: &str) {
 	println!("\x1b[32m  Options:\x1b[0m");
 	println!("    \x1b[90m-f\x1b[0m         Force recompile of do.it script.");
 	println!("    \x1b[90m-t <file>\x1b[0m  Provide a file path to the do.it file if not in CWD.");
-	println!("    \x1b[90m-c\x1b[0m         Clean the current directory by removing the .doit directory.");
+	println!("    \x1b[90m-n\x1b[0m         Dry run: print the scripts of the target with their arguments and variables injected,");
+	println!("               and the values of its variables, without running anything.");
+	println!("    \x1b[90m-v\x1b[0m         Trace each script to stderr with its line in the do.it file, its exit code and duration.");
+	println!("    \x1b[90m-x\x1b[0m         Like \x1b[90m-v\x1b[0m, and also trace the assignments of variables.");
+	println!("    \x1b[90m-c\x1b[0m         Clean the cache of the do.it file. Use \x1b[90m-c --all\x1b[0m to clear the cache of every do.it file.");
+	println!(
+		"    \x1b[90m--gc [days]\x1b[0m  Remove caches of do.it files that no longer exist, or were not used for {} days.",
+		DEFAULT_GC_DAYS
+	);
+	println!("    \x1b[90m--backend=<cpp|interp>\x1b[0m  Compile the targets to C++, or run them with the built-in interpreter.");
+	println!("               Defaults to C++, and to the interpreter when no C++ compiler is installed.");
+	println!("    \x1b[90m--export <path>\x1b[0m  Copy the compiled targets to a standalone binary. Add \x1b[90m--static\x1b[0m to link it statically.");
+	println!("    \x1b[90m--explain <code>\x1b[0m  Print a detailed explanation of an error code (ie. E004).");
 	println!();
 	println!("\x1b[32m  Dev Options:\x1b[0m");
-	println!("    \x1b[90m--tokens\x1b[0m   Print out the lexical tokens instead of fully compiling.");
-	println!("    \x1b[90m--nodes\x1b[0m    Print out the parser node tree instead of fully compiling.");
+	println!("    \x1b[90m--tokens\x1b[0m   Print out the lexical tokens with their source spans instead of fully compiling.");
+	println!("    \x1b[90m--nodes\x1b[0m    Print out the parser node tree with source spans instead of fully compiling.");
 	println!("    \x1b[90m--source\x1b[0m   Print the transpiled C++ code to stdout instead of fully compiling.");
-	println!("    \x1b[90m--keep\x1b[0m     After compiling, do not delete the .doit/targets.cpp file.");
+	println!("    \x1b[90m--emit <cpp|sh>\x1b[0m  Print the output of a code generation backend. \x1b[90m--emit sh\x1b[0m prints a standalone");
+	println!("               POSIX shell script that runs the targets without doit.");
+	println!("    \x1b[90m--keep\x1b[0m     After compiling, do not delete the generated targets.cpp and runtime.cpp files.");
+	println!("    \x1b[90m--timings\x1b[0m  Print how long each step of compiling the do.it file took.");
+	println!("    \x1b[90m--time\x1b[0m     After the run, print how long the compile, the target and each of its scripts took.");
+	println!("    \x1b[90m--trace <file>\x1b[0m  Record the compile, the target and its scripts to a Chrome trace-event file,");
+	println!("               or to JSON lines when the file ends in \x1b[90m.jsonl\x1b[0m.");
+	println!("    \x1b[90m--junit <file>\x1b[0m  Write a JUnit XML report with a testcase for each script of the target, and the");
+	println!("               stdout and stderr of the scripts that failed.");
 	println!("    \x1b[90m--targets\x1b[0m  Print the targets to stdout. Used for autocompletion.");
 	println!("    \x1b[90m--help\x1b[0m     Prints out this help message.");
 }
@@ -44,16 +78,58 @@ fn main() -> ExitCode {
 	let mut print_nodes = false;
 	let mut print_source = false;
 	let mut print_targets = false;
+	let mut emit: Option<String> = None;
 	let mut keep_source = false;
+	let mut timings = false;
+	let mut time = false;
+	let mut trace: Option<PathBuf> = None;
+	let mut junit: Option<PathBuf> = None;
+	let mut clean = false;
+	let mut clean_all = false;
+	let mut gc_days: Option<u64> = None;
+	let mut backend = Backend::Auto;
+	let mut export: Option<PathBuf> = None;
+	let mut static_link = false;
+	let mut options = RunOptions::default();
 	let mut filename: String = String::new();
 	while !args.is_empty() && args[0].starts_with('-') {
 		match args.remove(0).as_str() {
 			"-f" => force_recompile = true,
+			"-n" => options.dry_run = true,
+			"-v" => options.verbose = options.verbose.max(1),
+			"-x" => options.verbose = 2,
 			"--tokens" => print_tokens = true,
 			"--nodes" => print_nodes = true,
 			"--source" => print_source = true,
 			"--keep" => keep_source = true,
+			"--timings" => timings = true,
+			"--time" => time = true,
 			"--targets" => print_targets = true,
+			"--trace" => {
+				if args.is_empty() {
+					log::error("Missing the path to write the trace to, ie. `doit --trace trace.json build`");
+					return ExitCode::from(1);
+				}
+				trace = Some(PathBuf::from(args.remove(0)));
+			}
+			"--junit" => {
+				if args.is_empty() {
+					log::error("Missing the path to write the report to, ie. `doit --junit report.xml test`");
+					return ExitCode::from(1);
+				}
+				junit = Some(PathBuf::from(args.remove(0)));
+				options.capture = true;
+			}
+			"--emit" => match args.first().filter(|name| backend::find(name).is_some()) {
+				Some(_) => emit = Some(args.remove(0)),
+				None => {
+					let names: Vec<String> =
+						backend::builtin().iter().map(|backend| format!("'{}'", backend.name())).collect();
+					let format = args.first().map(String::as_str).unwrap_or_default();
+					log::error(&format!("Unknown emit format: '{}'. Expected {}.", format, names.join(" or ")));
+					return ExitCode::from(1);
+				}
+			},
 			"--completion" => {
 				println!(
 					r##"#!/bin/bash
@@ -74,16 +150,50 @@ complete -F _doit_completions doit"##
 				println!("v{}", env!("CARGO_PKG_VERSION"));
 				exit(0);
 			}
+			"--explain" => {
+				let code = if args.is_empty() { String::new() } else { args.remove(0) };
+				match diagnostics::codes::explain(&code) {
+					Some(text) => {
+						println!("\x1b[1m{}\x1b[0m\n\n{}", code.to_uppercase(), text);
+						exit(0);
+					}
+					None => {
+						log::error(&format!("Unknown error code: '{}'", code));
+						return ExitCode::from(1);
+					}
+				}
+			}
 			"-t" => filename = args.remove(0),
-			"-c" => {
-				if Path::new("./.doit").exists()
-					&& let Err(err) = fs::remove_dir_all("./.doit") {
-					log::error(&err.to_string());
+			"--export" => {
+				if args.is_empty() {
+					log::error("Missing the path to export the targets to, ie. `doit --export ./bin/tasks`");
 					return ExitCode::from(1);
 				}
-				log::info("Cleaned!");
-				exit(0);
+				export = Some(PathBuf::from(args.remove(0)));
+			}
+			"--static" => static_link = true,
+			"-c" => {
+				clean = true;
+				if args.first().is_some_and(|arg| arg == "--all") {
+					clean_all = true;
+					args.remove(0);
+				}
 			}
+			"--gc" => {
+				gc_days = Some(DEFAULT_GC_DAYS);
+				if let Some(days) = args.first().and_then(|arg| arg.parse().ok()) {
+					gc_days = Some(days);
+					args.remove(0);
+				}
+			}
+			option if option.starts_with("--backend=") => match &option["--backend=".len()..] {
+				"cpp" => backend = Backend::Cpp,
+				"interp" => backend = Backend::Interp,
+				name => {
+					log::error(&format!("Unknown backend: '{}'. Expected 'cpp' or 'interp'.", name));
+					return ExitCode::from(1);
+				}
+			},
 			fail => {
 				log::error(&format!("Unknown option: {}", fail));
 				print_help(&program_name);
@@ -99,6 +209,27 @@ complete -F _doit_completions doit"##
 		}
 	}
 
+	if clean || gc_days.is_some() {
+		// The do.it file may already be gone, which is exactly when its cache needs cleaning up
+		let absolute = fs::canonicalize(&filename)
+			.unwrap_or_else(|_| env::current_dir().unwrap_or_default().join(&filename))
+			.to_string_lossy()
+			.to_string();
+		let root = cache::cache_root(&absolute);
+		let result = match gc_days {
+			Some(days) => collect_garbage(&root, days),
+			None if clean_all => clean_all_caches(&root),
+			None => clean_cache(&cache::cache_directory(&root, &absolute)),
+		};
+		return match result {
+			Ok(()) => ExitCode::from(0),
+			Err(err) => {
+				log::error(&err.to_string());
+				ExitCode::from(DoitError::from(err).exit_code())
+			}
+		};
+	}
+
 	match fs::canonicalize(Path::new(&filename)) {
 		Ok(path) => {
 			if let Some(path_str) = path.to_str() {
@@ -126,48 +257,153 @@ complete -F _doit_completions doit"##
 		return ExitCode::from(1);
 	}
 
-	let directory = &format!("./.doit/{}", utils::hash::calculate_hash(&filename));
+	let directory = &cache::cache_directory(&cache::cache_root(&filename), &filename).to_string_lossy().to_string();
 	log::debug(&format!("Output directory: {}", directory));
 
-	if let Err(err) = compiler::build(
-		directory,
-		&filename,
-		keep_source,
-		force_recompile,
-		if print_tokens {
-			CompileMode::PRINT_TOKENS
-		} else if print_nodes {
-			CompileMode::PRINT_NODES
-		} else if print_source {
-			CompileMode::PRINT_SOURCE
-		} else if print_targets {
-			CompileMode::PRINT_TARGETS
-		} else {
-			CompileMode::NORMAL
-		},
-	) {
-		log::error(&err.to_string());
+	if static_link && export.is_none() {
+		log::error("--static can only be used together with --export");
 		return ExitCode::from(1);
 	}
-	if print_tokens || print_nodes || print_source || print_targets {
-		return ExitCode::from(0);
+	if let Some(destination) = export {
+		return match compiler::export(directory, &filename, &destination, static_link) {
+			Ok(()) => {
+				log::info(&format!("Exported the targets to {}", destination.display()));
+				ExitCode::from(0)
+			}
+			Err(err) => {
+				log::error(&err.summary());
+				ExitCode::from(err.exit_code())
+			}
+		};
+	}
+
+	if time || trace.is_some() || junit.is_some() {
+		options.events = Some(env::temp_dir().join(format!("doit-events-{}.jsonl", std::process::id())));
 	}
+	let record = |kind: EventKind, status: i32| {
+		if let Some(path) = &options.events {
+			let _ = events::record(path, &Event::now(kind, "", 0, status));
+		}
+	};
 
-	let child = Command::new(directory.to_owned() + "/targets").args(&args).spawn();
-	if let Err(err) = child {
-		log::error(&err.to_string());
-		ExitCode::from(0)
-	} else {
-		match child.unwrap().wait() {
-			Ok(status) => {
-				let code = status.code().unwrap_or(if status.success() { 0 } else { 1 });
-				log::debug(&format!("Exit Code: {}", code));
-				ExitCode::from(code as u8)
+	let inspect = print_tokens || print_nodes || print_source || print_targets || emit.is_some();
+	if !inspect && backend != Backend::Interp {
+		record(EventKind::CompileStart, 0);
+	}
+	if inspect || backend != Backend::Interp {
+		let result = compiler::build(
+			directory,
+			&filename,
+			keep_source,
+			force_recompile,
+			timings,
+			if print_tokens {
+				CompileMode::PRINT_TOKENS
+			} else if print_nodes {
+				CompileMode::PRINT_NODES
+			} else if print_source {
+				CompileMode::PRINT_SOURCE
+			} else if print_targets {
+				CompileMode::PRINT_TARGETS
+			} else if let Some(name) = emit {
+				CompileMode::EMIT(name)
+			} else {
+				CompileMode::NORMAL
+			},
+		);
+		if !inspect {
+			record(EventKind::CompileEnd, if result.is_ok() { 0 } else { 1 });
+		}
+		match result {
+			Ok(()) if inspect => return ExitCode::from(0),
+			Ok(()) => backend = Backend::Cpp,
+			Err(DoitError::Toolchain(message)) if backend == Backend::Auto && toolchain::compiler_missing() => {
+				log::warn(&format!("Falling back to the interpreter: {}", message));
+				backend = Backend::Interp;
 			}
 			Err(err) => {
-				log::error(&err.to_string());
-				ExitCode::from(1)
+				report(&options, &filename, time, trace.as_deref(), junit.as_deref());
+				log::error(&err.summary());
+				return ExitCode::from(err.exit_code());
 			}
 		}
 	}
+
+	let result = match backend {
+		Backend::Interp => interpreter::run(&filename, &args, &options),
+		_ => run(directory, &args, &options),
+	};
+	report(&options, &filename, time, trace.as_deref(), junit.as_deref());
+	match result {
+		Ok(code) => ExitCode::from(code),
+		Err(err) => {
+			log::error(&err.summary());
+			ExitCode::from(err.exit_code())
+		}
+	}
+}
+
+/** Prints the reports that were asked for from the events of the run. */
+fn report(options: &RunOptions, filename: &str, time: bool, trace: Option<&Path>, junit: Option<&Path>) {
+	let Some(path) = &options.events else {
+		return;
+	};
+	let recorded = events::load(path);
+	if time {
+		eprint!("{}", events::render_times(&recorded));
+	}
+	if let Some(trace) = trace {
+		let contents = if trace.extension().is_some_and(|extension| extension == "jsonl") {
+			events::render_json_lines(&recorded)
+		} else {
+			events::render_chrome_trace(&recorded)
+		};
+		if let Err(err) = fs::write(trace, contents) {
+			log::error(&format!("Failed to write the trace to {}: {}", trace.display(), err));
+		}
+	}
+	if let Some(junit) = junit
+		&& let Err(err) = fs::write(junit, events::render_junit(filename, &recorded))
+	{
+		log::error(&format!("Failed to write the JUnit report to {}: {}", junit.display(), err));
+	}
+	let _ = fs::remove_file(path);
+}
+
+fn clean_cache(directory: &Path) -> io::Result<()> {
+	if directory.exists() {
+		fs::remove_dir_all(directory)?;
+	}
+	log::info("Cleaned!");
+	Ok(())
+}
+
+fn clean_all_caches(root: &Path) -> io::Result<()> {
+	let removed = cache::clean_all(root)?;
+	removed.iter().for_each(|directory| log::debug(&format!("Removed {}", directory.display())));
+	log::info("Cleaned!");
+	Ok(())
+}
+
+fn collect_garbage(root: &Path, days: u64) -> io::Result<()> {
+	let removed = cache::collect_garbage(root, days)?;
+	removed.iter().for_each(|directory| log::debug(&format!("Removed {}", directory.display())));
+	log::info(&format!("Removed {} unused cache(s) from {}", removed.len(), root.display()));
+	Ok(())
+}
+
+/** Runs the compiled targets binary, passing its exit code through untouched. */
+fn run(directory: &str, args: &[String], options: &RunOptions) -> Result<u8, DoitError> {
+	// The binary prints its own name in its usage, which is the name it was run through
+	let mut command = Command::new(directory.to_owned() + "/targets");
+	command.arg0("doit").args(args).envs(options.env());
+	let status = signals::run(command, |_| {})
+		.map_err(|err| DoitError::Runtime(format!("Failed to run the targets binary: {err}")))?;
+	// The runtime reports the scripts that were killed, so only the binary itself being killed is left to report
+	if let Some(signal) = status.signal() {
+		log::error(&format!("The targets were killed by {}", signals::name(signal)));
+	}
+	let code = signals::exit_code(&status);
+	log::debug(&format!("Exit Code: {}", code));
+	Ok(code)
 }
@@ -0,0 +1,30 @@
+use std::path::PathBuf;
+
+/**
+Options that change how the targets run rather than how they are built. The compiled targets binary reads them from
+its environment, so that changing them never invalidates the cache, and the interpreter takes them directly.
+*/
+#[derive(Default, Clone)]
+pub struct RunOptions {
+	/** Print the scripts and assignments of the target instead of running anything */
+	pub dry_run: bool,
+	/** 1 traces every script with its location, exit code and duration, 2 also traces assignments */
+	pub verbose: u8,
+	/** Keep the stdout and stderr of a failing script for the reports, while still printing them */
+	pub capture: bool,
+	/** The file that the run appends its [`Event`](crate::events::Event)s to */
+	pub events: Option<PathBuf>,
+}
+
+impl RunOptions {
+	/** The variables that [`RunOptions::env`] sets, which scripts never inherit, so a doit they run starts afresh */
+	pub const ENV_NAMES: [&'static str; 4] = ["DOIT_DRY_RUN", "DOIT_VERBOSE", "DOIT_CAPTURE", "DOIT_EVENTS"];
+
+	/** The environment variables that pass the options to the targets binary. */
+	pub fn env(&self) -> Vec<(&'static str, String)> {
+		let flag = |enabled: bool| if enabled { "1" } else { "0" }.to_string();
+		let events = self.events.as_ref().map(|path| path.to_string_lossy().to_string()).unwrap_or_default();
+		let values = [flag(self.dry_run), self.verbose.to_string(), flag(self.capture), events];
+		RunOptions::ENV_NAMES.into_iter().zip(values).collect()
+	}
+}
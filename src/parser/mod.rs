@@ -1,38 +1,90 @@
-use std::io::{Error, ErrorKind};
-
-use crate::lexer::token::{TokenType, Tokenizer};
+use crate::{
+	diagnostics::{Diagnostic, codes},
+	error::DoitError,
+	lexer::token::{Span, Token, TokenType, Tokenizer},
+};
 
 use self::nodes::{Node, NodeType};
 
 pub mod nodes;
 
+/** Formats a token for an error message without its span, which the diagnostic already points at. */
+fn describe(token: &Token) -> String {
+	match &token.value {
+		Some(value) => format!("{:?}({})", token.ttype, value),
+		None => format!("{:?}", token.ttype),
+	}
+}
+
 pub struct Parser<'parser> {
 	tokenizer: &'parser mut dyn Tokenizer,
+	/** Syntax errors that have been recovered from so far */
+	diagnostics: Vec<Diagnostic>,
+	/** Whether any of the recovered errors came from the tokenizer rather than the parser */
+	lexical: bool,
 }
 
 impl Parser<'_> {
 	pub fn new(tokenizer: &mut dyn Tokenizer) -> Parser<'_> {
-		Parser { tokenizer }
+		Parser { tokenizer, diagnostics: vec![], lexical: false }
+	}
+	pub fn get_filename(&self) -> &str {
+		self.tokenizer.get_filename()
+	}
+	fn generate_error(&self, code: &'static str, span: Span, message: &str) -> Diagnostic {
+		Diagnostic::error(code, message, span)
+	}
+	fn fail<T>(&self, diagnostic: Diagnostic) -> Result<T, DoitError> {
+		Err(DoitError::Parse(vec![diagnostic]))
+	}
+
+	/** Records the diagnostics carried by `error`. Anything that is not a syntax error can not be recovered from. */
+	fn record(&mut self, error: DoitError) -> Result<(), DoitError> {
+		match error {
+			DoitError::Lex(diagnostics) => {
+				self.lexical = true;
+				self.diagnostics.extend(diagnostics);
+			}
+			DoitError::Parse(diagnostics) => self.diagnostics.extend(diagnostics),
+			_ => return Err(error),
+		}
+		Ok(())
 	}
-	fn generate_error(&self, error_kind: ErrorKind, message: &str) -> Error {
-		Error::new(
-			error_kind,
-			format!(
-				"{}:{}:{} > {message}",
-				self.tokenizer.get_filename(),
-				self.tokenizer.get_lineno(),
-				self.tokenizer.get_charno()
-			),
-		)
+
+	/** Records the diagnostics carried by `error` and skips ahead to a point where parsing can resume. */
+	fn recover(&mut self, error: DoitError) -> Result<(), DoitError> {
+		self.record(error)?;
+		self.synchronise()
 	}
-	fn handle_error(&self, read: Result<Node, Error>) -> Result<Node, Error> {
-		match read {
-			Ok(v) => Ok(v),
-			Err(e) => Err(self.generate_error(ErrorKind::InvalidData, &e.to_string())),
+
+	/** Skips to the end of the current line, leaving a `}` or EOF for the enclosing scope to consume. */
+	fn synchronise(&mut self) -> Result<(), DoitError> {
+		// Any `{ ... }` opened along the way is skipped as a whole so its body is not parsed out of context
+		let mut depth = 0;
+		loop {
+			let ttype = match self.tokenizer.peek_token() {
+				Ok(token) => token.ttype.clone(),
+				Err(error) => {
+					self.record(error)?;
+					continue;
+				}
+			};
+			match ttype {
+				TokenType::EOF => return Ok(()),
+				TokenType::EOL if depth == 0 => {
+					self.tokenizer.next_token()?;
+					return Ok(());
+				}
+				TokenType::TGT_END if depth == 0 => return Ok(()),
+				TokenType::TGT_END => depth -= 1,
+				TokenType::TGT_BEG => depth += 1,
+				_ => {}
+			}
+			self.tokenizer.next_token()?;
 		}
 	}
 
-	fn parse_expression(&mut self) -> Result<Vec<Node>, Error> {
+	fn parse_expression(&mut self) -> Result<Vec<Node>, DoitError> {
 		let mut nodes: Vec<Node> = vec![];
 		while self.tokenizer.peek_token()?.ttype != TokenType::EOL
 			&& self.tokenizer.peek_token()?.ttype != TokenType::EOF
@@ -42,34 +94,58 @@ impl Parser<'_> {
 		Ok(nodes)
 	}
 
-	fn parse_nomenclature(&mut self, scope: &mut Node) -> Result<(), Error> {
+	fn parse_nomenclature(&mut self, scope: &mut Node) -> Result<(), DoitError> {
 		let name = self.tokenizer.next_token()?;
 		loop {
 			let next = self.tokenizer.next_token()?;
 			match next.ttype {
 				// Ignore EOL tokens that occur between the NOMEN and the next token
 				TokenType::EOL => continue,
-				TokenType::EOF => return Err(self.generate_error(ErrorKind::UnexpectedEof, "Unexpected end of file")),
+				TokenType::EOF => {
+					let diagnostic = self
+						.generate_error(codes::UNEXPECTED_EOF, next.span, "Unexpected end of file")
+						.with_secondary(name.span, "expected `=`, `{` or `:` after this name");
+					return self.fail(diagnostic);
+				}
 				TokenType::ASSIGN => scope
 					.children
 					.push(Node::new(NodeType::ASSIGN, name, self.parse_expression()?)),
 				TokenType::TGT_BEG => {
 					let mut block = Node::single(NodeType::TARGET, name);
+					block.extend(&next.span);
 					self.parse_scope(&mut block)?;
 					scope.children.push(block);
 				}
 				TokenType::TGT_SLE => {
-					let script = Node::single(NodeType::SCR_SH, self.tokenizer.next_token()?);
-					scope.children.push(Node::new(NodeType::TARGET, name, vec![script]));
+					let script = self.tokenizer.next_token()?;
+					let ntype = match &script.ttype {
+						TokenType::SCRIPT(kind) => NodeType::SCRIPT(kind.clone()),
+						_ => {
+							let diagnostic = self
+								.generate_error(
+									codes::UNEXPECTED_TOKEN,
+									script.span,
+									&format!("Encountered unexpected Token: {}", describe(&script)),
+								)
+								.with_label("expected a `$` or `%` script")
+								.with_secondary(name.span, "single line target defined here");
+							return self.fail(diagnostic);
+						}
+					};
+					let mut target = Node::new(NodeType::TARGET, name, vec![Node::single(ntype, script)]);
+					target.extend(&next.span);
+					scope.children.push(target);
 				}
-				token => {
-					return Err(self.generate_error(
-						ErrorKind::InvalidData,
-						&format!(
-							"Encountered unexpected Token: {token:?}({})",
-							next.value.unwrap_or("".to_string())
-						),
-					))
+				_ => {
+					let diagnostic = self
+						.generate_error(
+							codes::UNEXPECTED_TOKEN,
+							next.span,
+							&format!("Encountered unexpected Token: {}", describe(&next)),
+						)
+						.with_label("expected `=`, `{` or `:`")
+						.with_secondary(name.span, "while parsing this name");
+					return self.fail(diagnostic);
 				}
 			}
 			break;
@@ -77,88 +153,140 @@ impl Parser<'_> {
 		Ok(())
 	}
 
-	fn parse_argdef(&mut self, scope: &mut Node, ntype: NodeType) -> Result<(), Error> {
+	fn parse_argdef(&mut self, scope: &mut Node, ntype: NodeType) -> Result<(), DoitError> {
 		let mut node = Node::single(ntype, self.tokenizer.next_token()?);
 		if self.tokenizer.peek_token()?.ttype == TokenType::HELP {
-			node.help = Some(self.tokenizer.next_token()?);
+			let help = self.tokenizer.next_token()?;
+			node.extend(&help.span);
+			node.help = Some(help);
 		}
 		scope.children.push(node);
 		Ok(())
 	}
 
-	fn parse_scope(&mut self, scope: &mut Node) -> Result<(), Error> {
+	fn parse_scope(&mut self, scope: &mut Node) -> Result<(), DoitError> {
 		loop {
-			let next = self.tokenizer.peek_token()?;
-			match next.ttype {
-				// Ignore dangling EOL tokens (empty lines)
-				TokenType::EOL => {
-					self.tokenizer.next_token()?;
+			let next = match self.tokenizer.peek_token() {
+				Ok(next) => next,
+				Err(error) => {
+					self.recover(error)?;
 					continue;
-				},
-				TokenType::EOF | TokenType::TGT_END => {
-					self.tokenizer.next_token()?;
+				}
+			};
+			let result = match next.ttype {
+				// Ignore dangling EOL tokens (empty lines)
+				TokenType::EOL => self.tokenizer.next_token().map(|_| ()),
+				TokenType::EOF => {
+					let end = self.tokenizer.next_token()?;
+					scope.extend(&end.span);
+					if scope.ntype != NodeType::ROOT {
+						self.diagnostics.push(
+							self.generate_error(codes::UNEXPECTED_EOF, end.span, "Unexpected end of file")
+								.with_label("expected `}`")
+								.with_secondary(scope.value.span, "target is missing its closing `}`"),
+						);
+					}
+					return Ok(());
+				}
+				TokenType::TGT_END if scope.ntype != NodeType::ROOT => {
+					let end = self.tokenizer.next_token()?;
+					scope.extend(&end.span);
 					return Ok(());
-				},
-				TokenType::EXIT => scope.children.push(Node::new(
-					NodeType::EXIT,
-					self.tokenizer.next_token()?,
-					self.parse_expression()?,
-				)),
-				TokenType::YIELD => scope.children.push(Node::single(
-					NodeType::YIELD,
-					self.tokenizer.next_token()?,
-				)),
-				TokenType::NOMEN => self.parse_nomenclature(scope)?,
-				TokenType::SCR_SH => scope.children.push(Node::new(
-					NodeType::SCR_SH,
-					self.tokenizer.next_token()?,
-					self.parse_expression()?,
-				)),
-				TokenType::SCR_PY => scope.children.push(Node::new(
-					NodeType::SCR_PY,
-					self.tokenizer.next_token()?,
-					self.parse_expression()?,
-				)),
-				TokenType::COMMENT => scope.children.push(Node::new(
-					NodeType::COMMENT,
-					self.tokenizer.next_token()?,
-					self.parse_expression()?,
-				)),
+				}
+				TokenType::EXIT => self.tokenizer.next_token().and_then(|token| {
+					let node = Node::new(NodeType::EXIT, token, self.parse_expression()?);
+					scope.children.push(node);
+					Ok(())
+				}),
+				TokenType::YIELD => self
+					.tokenizer
+					.next_token()
+					.map(|token| scope.children.push(Node::single(NodeType::YIELD, token))),
+				TokenType::NOMEN => self.parse_nomenclature(scope),
+				TokenType::SCRIPT(_) | TokenType::COMMENT => {
+					let ntype = match &next.ttype {
+						TokenType::SCRIPT(kind) => NodeType::SCRIPT(kind.clone()),
+						_ => NodeType::COMMENT,
+					};
+					self.tokenizer.next_token().and_then(|token| {
+						let node = Node::new(ntype, token, self.parse_expression()?);
+						scope.children.push(node);
+						Ok(())
+					})
+				}
 				TokenType::HELP => {
-					if scope.help.is_some() {
-						return Err(Error::other(
-							"Help block has already been defined for the current scope.",
-						));
+					if let Some(help) = scope.help.as_ref() {
+						let span = next.span;
+						let diagnostic = self
+							.generate_error(
+								codes::DUPLICATE_HELP,
+								span,
+								"Help block has already been defined for the current scope.",
+							)
+							.with_label("second help block")
+							.with_secondary(help.span, "first defined here");
+						self.fail(diagnostic)
+					} else {
+						self.tokenizer.next_token().map(|help| {
+							scope.extend(&help.span);
+							scope.help = Some(help);
+						})
 					}
-					scope.help = Some(self.tokenizer.next_token()?);
 				}
-				TokenType::ARG_REQ => self.parse_argdef(scope, NodeType::ARG_REQ)?,
-				TokenType::ARG_OPT => self.parse_argdef(scope, NodeType::ARG_OPT)?,
-				_ => panic!("Encountered unexpected Token: {}", next),
+				TokenType::ARG_REQ => self.parse_argdef(scope, NodeType::ARG_REQ),
+				TokenType::ARG_OPT => self.parse_argdef(scope, NodeType::ARG_OPT),
+				TokenType::TGT_END => self.tokenizer.next_token().map(|end| {
+					let diagnostic = self
+						.generate_error(codes::UNEXPECTED_TOKEN, end.span, "Encountered unexpected Token: TGT_END")
+						.with_label("unmatched `}`");
+					self.diagnostics.push(diagnostic);
+				}),
+				_ => {
+					let (span, message) = (
+						next.span,
+						format!("Encountered unexpected Token: {}", describe(next)),
+					);
+					let diagnostic = self
+						.generate_error(codes::UNEXPECTED_TOKEN, span, &message)
+						.with_label("expected a statement");
+					self.fail(diagnostic)
+				}
+			};
+			if let Err(error) = result {
+				self.recover(error)?;
 			}
 		}
 	}
 
-	pub fn parse(&mut self) -> Result<Node, Error> {
+	pub fn parse(&mut self) -> Result<Node, DoitError> {
 		let root_token = self.tokenizer.next_token()?;
 		if root_token.ttype != TokenType::SOF {
-			return Err(self.generate_error(
-				ErrorKind::NotFound,
-				"Missing Start of File token. Has the tokenizer already been iterated on?",
-			));
+			let diagnostic = self
+				.generate_error(
+					codes::INTERNAL,
+					root_token.span,
+					"Missing Start of File token. Has the tokenizer already been iterated on?",
+				);
+			return self.fail(diagnostic);
 		}
 		let mut root_node = Node::single(NodeType::ROOT, root_token);
 		self.parse_scope(&mut root_node)?;
+		if !self.diagnostics.is_empty() {
+			let diagnostics = std::mem::take(&mut self.diagnostics);
+			return Err(if self.lexical { DoitError::Lex(diagnostics) } else { DoitError::Parse(diagnostics) });
+		}
 		Ok(root_node)
 	}
 }
 
 #[cfg(test)]
 mod tests {
-	use std::io::Error;
-
 	use crate::{
-		lexer::token::{Token, TokenType, Tokenizer},
+		error::DoitError,
+		lexer::{
+			token::{ScriptKind, Span, Token, TokenType, Tokenizer},
+			Lexer,
+		},
 		parser::nodes::NodeType,
 	};
 
@@ -169,14 +297,14 @@ mod tests {
 		pub index: usize,
 	}
 	impl Tokenizer for MockTockenizer {
-		fn peek_token(&mut self) -> Result<&crate::lexer::token::Token, std::io::Error> {
+		fn peek_token(&mut self) -> Result<&crate::lexer::token::Token, DoitError> {
 			self.garbage.push(Token::val(
 				self.tokens[self.index].0.clone(),
 				self.tokens[self.index].1.clone(),
 			));
 			Ok(self.garbage.last().unwrap())
 		}
-		fn next_token(&mut self) -> Result<crate::lexer::token::Token, std::io::Error> {
+		fn next_token(&mut self) -> Result<crate::lexer::token::Token, DoitError> {
 			let token = Token::val(self.tokens[self.index].0.clone(), self.tokens[self.index].1.clone());
 			self.index += 1;
 			Ok(token)
@@ -208,7 +336,7 @@ mod tests {
 	}
 
 	#[test]
-	fn test_parser() -> Result<(), Error> {
+	fn test_parser() -> Result<(), DoitError> {
 		let mut mock_tockenizer = MockTockenizer {
 			garbage: vec![],
 			index: 0,
@@ -233,11 +361,11 @@ mod tests {
 				(TokenType::ARG_OPT, some("-d")),
 				(TokenType::HELP, some("help4")),
 				(TokenType::EOL, None),
-				(TokenType::SCR_SH, some("script1")),
+				(TokenType::SCRIPT(ScriptKind::Shell), some("script1")),
 				(TokenType::EOL, None),
 				(TokenType::COMMENT, some("comment2")),
 				(TokenType::EOL, None),
-				(TokenType::SCR_PY, some("python1")),
+				(TokenType::SCRIPT(ScriptKind::Python), some("python1")),
 				(TokenType::EOL, None),
 				(TokenType::YIELD, None),
 				(TokenType::EOL, None),
@@ -245,7 +373,7 @@ mod tests {
 				(TokenType::EOL, None),
 				(TokenType::NOMEN, some("test2")),
 				(TokenType::TGT_SLE, None),
-				(TokenType::SCR_SH, some("script2")),
+				(TokenType::SCRIPT(ScriptKind::Shell), some("script2")),
 				(TokenType::EOL, None),
 				(TokenType::EOF, None),
 			],
@@ -267,7 +395,7 @@ mod tests {
 
 		check_node(
 			root.children.get(1).unwrap().children.get(4),
-			NodeType::SCR_SH,
+			NodeType::SCRIPT(ScriptKind::Shell),
 			"script1",
 		);
 		check_node(
@@ -277,16 +405,56 @@ mod tests {
 		);
 		check_node(
 			root.children.get(1).unwrap().children.get(6),
-			NodeType::SCR_PY,
+			NodeType::SCRIPT(ScriptKind::Python),
 			"python1",
 		);
 		check_node(root.children.get(2), NodeType::TARGET, "test2");
 		assert!(root.children.get(2).unwrap().help.is_none());
 		check_node(
 			root.children.get(2).unwrap().children.first(),
-			NodeType::SCR_SH,
+			NodeType::SCRIPT(ScriptKind::Shell),
 			"script2",
 		);
 		Ok(())
 	}
+
+	#[test]
+	fn test_parser_spans() -> Result<(), DoitError> {
+		let mut lexer = Lexer::new("test-do.it", "var = 1 + 2\ntgt {\n\topt -a @ help\n\t$ ls\n}\nsle: $ ls\n");
+		let root = Parser::new(&mut lexer).parse()?;
+		assert_eq!(root.span, Span::new(1, 1, 7, 1));
+		assert_eq!(root.children[0].span, Span::new(1, 1, 1, 11));
+		assert_eq!(root.children[1].span, Span::new(2, 1, 5, 1));
+		assert_eq!(root.children[1].children[0].span, Span::new(3, 2, 3, 14));
+		assert_eq!(root.children[1].children[1].span, Span::new(4, 2, 4, 5));
+		assert_eq!(root.children[2].span, Span::new(6, 1, 6, 9));
+		Ok(())
+	}
+
+	#[test]
+	fn test_parser_recovery() {
+		let mut lexer = Lexer::new(
+			"test-do.it",
+			"\"stray\"\nbad ? {\n\t$ ls\n}\ngood {\n\t: oops\n\t@ one\n\t@ two\n}\n}\nsle: 42\nlast {\n",
+		);
+		let error = Parser::new(&mut lexer).parse().err().unwrap();
+		assert!(matches!(error, DoitError::Parse(_)));
+		let found: Vec<(Option<&str>, usize)> = error
+			.diagnostics()
+			.iter()
+			.map(|diagnostic| (diagnostic.code, diagnostic.span.start_line))
+			.collect();
+		assert_eq!(
+			found,
+			vec![
+				(Some("E004"), 1),
+				(Some("E004"), 2),
+				(Some("E004"), 6),
+				(Some("E005"), 8),
+				(Some("E004"), 10),
+				(Some("E004"), 11),
+				(Some("E001"), 13),
+			]
+		);
+	}
 }
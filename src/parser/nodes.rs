@@ -1,7 +1,7 @@
 use core::fmt;
 use std::fmt::Debug;
 
-use crate::lexer::token::Token;
+use crate::lexer::token::{ScriptKind, Span, Token};
 
 #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq)]
@@ -11,8 +11,7 @@ pub enum NodeType {
 	YIELD,
 	ASSIGN,
 	TARGET,
-	SCR_SH,
-	SCR_PY,
+	SCRIPT(ScriptKind),
 	COMMENT,
 	SYMBOL,
 	ARG_REQ,
@@ -24,6 +23,8 @@ pub struct Node {
 	pub help: Option<Token>,
 	pub ntype: NodeType,
 	pub children: Vec<Node>,
+	/** Source range covered by the node's value token, help token and all of its children. */
+	pub span: Span,
 }
 impl fmt::Display for Node {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -33,11 +34,12 @@ impl fmt::Display for Node {
 impl Node {
 	fn print(&self, indent: &str, last: bool, root: bool) -> String {
 		let mut result = format!(
-			"{}{}{:?}{}: {}\n",
+			"{}{}{:?}{} [{}]: {}\n",
 			indent,
 			if root { "" } else if last { "└─" } else { "├─" },
 			self.ntype,
 			if self.help.is_some() { " [HAS_HELP]" } else { "" },
+			self.span,
 			self.value.value.as_ref().unwrap_or(&String::new()),
 		);
 		let mut new_indent = format!("{}│ ", indent);
@@ -52,10 +54,15 @@ impl Node {
 		result
 	}
 
-	pub const fn new(ntype: NodeType, value: Token, children: Vec<Node>) -> Node {
-		Node { ntype, value, children, help: None }
+	pub fn new(ntype: NodeType, value: Token, children: Vec<Node>) -> Node {
+		let span = children.iter().fold(value.span, |span, child| span.to(&child.span));
+		Node { ntype, value, children, help: None, span }
 	}
-	pub const fn single(ntype: NodeType, value: Token) -> Node {
+	pub fn single(ntype: NodeType, value: Token) -> Node {
 		Node::new(ntype, value, vec![])
 	}
+	/** Grows the node's span to also cover `span`. */
+	pub fn extend(&mut self, span: &Span) {
+		self.span = self.span.to(span);
+	}
 }
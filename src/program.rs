@@ -0,0 +1,340 @@
+use crate::{
+	diagnostics::{Diagnostic, codes},
+	error::DoitError,
+	interpreter,
+	lexer::{
+		Lexer,
+		token::{Span, Token},
+	},
+	parser::{
+		Parser,
+		nodes::{Node, NodeType},
+	},
+};
+
+pub use crate::lexer::token::ScriptKind;
+
+const NO_HELP: &str = "\x1b[90m<No help defined>\x1b[0m";
+
+/**
+A validated do.it file, which every [`Backend`](crate::backend::Backend) works from instead of the raw parser nodes.
+Building one runs the checks that all backends rely on, so a do.it file is accepted or rejected the same way by each.
+*/
+pub struct Program {
+	pub filename: String,
+	/** The global help block, if any */
+	pub help: Option<String>,
+	/** Global variables and targets, in the order they appear in the do.it file */
+	pub items: Vec<Item>,
+}
+
+pub enum Item {
+	/** A global assignment or comment */
+	Statement(Statement),
+	Target(Target),
+}
+
+#[derive(Clone)]
+pub struct TargetArg {
+	pub required: bool,
+	pub name: String,
+	/** The help of the argument, or a grey `<No help defined>` */
+	pub help: String,
+}
+
+#[derive(Clone)]
+pub struct Target {
+	pub name: String,
+	/** The help of the target, or a grey `<No help defined>` */
+	pub help: String,
+	pub args: Vec<TargetArg>,
+	pub statements: Vec<Statement>,
+	/** The span of the target's name */
+	pub span: Span,
+}
+
+#[derive(Clone)]
+pub struct Statement {
+	pub kind: StatementKind,
+	pub span: Span,
+}
+
+#[derive(Clone)]
+pub enum StatementKind {
+	/** Declares a variable, or reassigns it if it is already in scope */
+	Assign { name: String, expression: Expression },
+	/** A `$`, `%` or `!!!` script, run by the program that its kind names */
+	Script { kind: ScriptKind, script: String },
+	/** Exits with the value of the expression, or with the status of the last script when there is none */
+	Exit(Option<Expression>),
+	/** Exits with the status of the last script if it failed */
+	Yield,
+	Comment(String),
+}
+
+/** The tokens of an assignment or exit expression: number and string literals, variable names and operators. */
+#[derive(Clone)]
+pub struct Expression {
+	pub tokens: Vec<Token>,
+}
+
+impl Program {
+	/** Lexes, parses and validates a do.it source. */
+	pub fn parse(filename: &str, source: &str) -> Result<Program, DoitError> {
+		let root = Parser::new(&mut Lexer::new(filename, source)).parse()?;
+		Program::new(filename, root)
+	}
+
+	/** Validates the node tree produced by the [`Parser`] and converts it into a program. */
+	pub fn new(filename: &str, root: Node) -> Result<Program, DoitError> {
+		validate(&root)?;
+		let mut items: Vec<Item> = vec![];
+		let mut diagnostics: Vec<Diagnostic> = vec![];
+		for node in root.children {
+			let item = match node.ntype {
+				NodeType::TARGET => target(node).map(|target| Some(Item::Target(target))),
+				_ => statement(node).map(|statement| statement.map(Item::Statement)),
+			};
+			match item {
+				Ok(Some(item)) => items.push(item),
+				Ok(None) => {}
+				Err(errors) => diagnostics.extend(errors),
+			}
+		}
+		if !diagnostics.is_empty() {
+			return Err(DoitError::Semantic(diagnostics));
+		}
+		let program = Program { filename: filename.to_string(), help: root.help.and_then(|help| help.value), items };
+		interpreter::check(&program)?;
+		Ok(program)
+	}
+
+	pub fn targets(&self) -> impl Iterator<Item = &Target> {
+		self.items.iter().filter_map(|item| match item {
+			Item::Target(target) => Some(target),
+			Item::Statement(_) => None,
+		})
+	}
+}
+
+fn help(node: &Node) -> String {
+	node.help.as_ref().and_then(|help| help.value.clone()).unwrap_or(NO_HELP.to_string())
+}
+
+fn target(node: Node) -> Result<Target, Vec<Diagnostic>> {
+	let mut target = Target {
+		name: node.value.value.clone().unwrap_or_default(),
+		help: help(&node),
+		args: vec![],
+		statements: vec![],
+		span: node.value.span,
+	};
+	let mut diagnostics: Vec<Diagnostic> = vec![];
+	for child in node.children {
+		if matches!(child.ntype, NodeType::ARG_REQ | NodeType::ARG_OPT) {
+			target.args.push(TargetArg {
+				required: child.ntype == NodeType::ARG_REQ,
+				help: help(&child),
+				name: child.value.value.unwrap_or_default(),
+			});
+			continue;
+		}
+		match statement(child) {
+			Ok(Some(statement)) => target.statements.push(statement),
+			Ok(None) => {}
+			Err(errors) => diagnostics.extend(errors),
+		}
+	}
+	if diagnostics.is_empty() { Ok(target) } else { Err(diagnostics) }
+}
+
+/** Converts a statement node, skipping the nodes that have no meaning of their own (ie. stray symbols). */
+fn statement(node: Node) -> Result<Option<Statement>, Vec<Diagnostic>> {
+	let span = node.span;
+	let value = node.value.value.clone().unwrap_or_default();
+	let kind = match node.ntype {
+		NodeType::ASSIGN => {
+			if node.value.value.is_none() {
+				return Err(vec![Diagnostic::error(codes::INTERNAL, "Assignment is missing a variable name", span)]);
+			}
+			if node.children.is_empty() {
+				let message = "Assignment is missing an expression";
+				let diagnostic = Diagnostic::error(codes::INVALID_EXPRESSION, message, span)
+					.with_label("expected a value after `=`");
+				return Err(vec![diagnostic]);
+			}
+			StatementKind::Assign { name: value, expression: expression(node.children)? }
+		}
+		NodeType::SCRIPT(ref kind) => StatementKind::Script { kind: kind.clone(), script: value },
+		NodeType::EXIT if node.children.is_empty() => StatementKind::Exit(None),
+		NodeType::EXIT => StatementKind::Exit(Some(expression(node.children)?)),
+		NodeType::YIELD => StatementKind::Yield,
+		NodeType::COMMENT => StatementKind::Comment(value),
+		NodeType::ROOT => return Err(vec![Diagnostic::error(codes::INTERNAL, "Unexpected ROOT node", span)]),
+		NodeType::TARGET => {
+			return Err(vec![Diagnostic::error(codes::MISPLACED_STATEMENT, "Targets can not be nested", span)]);
+		}
+		NodeType::SYMBOL | NodeType::ARG_REQ | NodeType::ARG_OPT => return Ok(None),
+	};
+	Ok(Some(Statement { kind, span }))
+}
+
+fn expression(nodes: Vec<Node>) -> Result<Expression, Vec<Diagnostic>> {
+	let mut tokens: Vec<Token> = vec![];
+	for node in nodes {
+		if node.ntype != NodeType::SYMBOL {
+			let message = format!("Unexpected node type in expression: {:?}", node.ntype);
+			return Err(vec![Diagnostic::error(codes::INVALID_EXPRESSION, &message, node.span)]);
+		}
+		// Tokens without a value (ie. `=`) carry no meaning in an expression
+		if node.value.value.is_some() {
+			tokens.push(node.value);
+		}
+	}
+	Ok(Expression { tokens })
+}
+
+/** Checks the rules that the parser does not enforce, but which every backend relies on. */
+fn validate(root: &Node) -> Result<(), DoitError> {
+	let mut diagnostics: Vec<Diagnostic> = vec![];
+	if let Err(error) = check_duplicate_targets(&root.children) {
+		diagnostics.extend_from_slice(error.diagnostics());
+	}
+	let mut globals: Vec<&str> = vec![];
+	for node in &root.children {
+		let message = match node.ntype {
+			NodeType::SCRIPT(_) => "Scripts can only be run inside of a target",
+			NodeType::EXIT => "`exit` can only be used inside of a target",
+			NodeType::YIELD => "`yield` can only be used inside of a target",
+			NodeType::ASSIGN if globals.contains(&node_value(node)) => {
+				"Global variables can only be reassigned inside of a target"
+			}
+			NodeType::ASSIGN => {
+				globals.push(node_value(node));
+				continue;
+			}
+			NodeType::TARGET => {
+				if let Err(error) = check_duplicate_targets(&node.children) {
+					diagnostics.extend_from_slice(error.diagnostics());
+				}
+				for nested in node.children.iter().filter(|child| child.ntype == NodeType::TARGET) {
+					let message = "Targets can not be nested inside of other targets";
+					diagnostics.push(
+						Diagnostic::error(codes::MISPLACED_STATEMENT, message, nested.value.span)
+							.with_secondary(node.value.span, "inside of this target"),
+					);
+				}
+				continue;
+			}
+			_ => continue,
+		};
+		diagnostics.push(Diagnostic::error(codes::MISPLACED_STATEMENT, message, node.span));
+	}
+	diagnostics.sort_by_key(|diagnostic| (diagnostic.span.start_line, diagnostic.span.start_col));
+	if diagnostics.is_empty() { Ok(()) } else { Err(DoitError::Semantic(diagnostics)) }
+}
+
+/** Targets share a single namespace in the generated C++, so a name may only be defined once per scope. */
+fn check_duplicate_targets(nodes: &[Node]) -> Result<(), DoitError> {
+	let mut diagnostics: Vec<Diagnostic> = vec![];
+	let targets: Vec<&Node> = nodes.iter().filter(|node| node.ntype == NodeType::TARGET).collect();
+	for (index, target) in targets.iter().enumerate() {
+		let name = node_value(target);
+		if let Some(first) = targets[..index].iter().find(|other| node_value(other) == name) {
+			diagnostics.push(
+				Diagnostic::error(codes::DUPLICATE_TARGET, &format!("Target '{name}' is defined multiple times"), target.value.span)
+					.with_label("redefined here")
+					.with_secondary(first.value.span, "first defined here"),
+			);
+		}
+	}
+	if diagnostics.is_empty() { Ok(()) } else { Err(DoitError::Semantic(diagnostics)) }
+}
+
+fn node_value(node: &Node) -> &str {
+	node.value.value.as_deref().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		error::DoitError,
+		lexer::token::{Span, Token, TokenType},
+		parser::nodes::{Node, NodeType},
+	};
+
+	use super::{Item, Program, ScriptKind, StatementKind, check_duplicate_targets, validate};
+
+	fn target(name: &str, line: usize) -> Node {
+		Node::single(
+			NodeType::TARGET,
+			Token::val(TokenType::NOMEN, Some(name.to_string())).with_span(Span::new(line, 1, line, name.len())),
+		)
+	}
+
+	#[test]
+	fn test_check_duplicate_targets() {
+		assert!(check_duplicate_targets(&[target("build", 1), target("test", 2)]).is_ok());
+		let error = check_duplicate_targets(&[target("build", 1), target("test", 2), target("build", 3)]).err().unwrap();
+		assert!(matches!(error, DoitError::Semantic(_)));
+		let diagnostics = error.diagnostics();
+		assert_eq!(diagnostics.len(), 1);
+		assert_eq!(diagnostics[0].code, Some("E009"));
+		assert_eq!(diagnostics[0].span.start_line, 3);
+		assert_eq!(diagnostics[0].labels[0].span.start_line, 1);
+	}
+
+	#[test]
+	fn test_validate() {
+		let mut root = Node::single(NodeType::ROOT, Token::sym(TokenType::SOF));
+		let mut outer = target("outer", 1);
+		outer.children.push(target("inner", 2));
+		let statement = |ntype: NodeType, value: &str, line: usize| {
+			Node::single(ntype, Token::val(TokenType::NOMEN, Some(value.to_string())).with_span(Span::new(line, 1, line, 5)))
+		};
+		root.children = vec![
+			outer,
+			statement(NodeType::SCRIPT(ScriptKind::Shell), "ls", 4),
+			statement(NodeType::ASSIGN, "x", 5),
+			statement(NodeType::ASSIGN, "x", 6),
+		];
+		let error = validate(&root).err().unwrap();
+		let lines: Vec<usize> = error.diagnostics().iter().map(|diagnostic| diagnostic.span.start_line).collect();
+		assert_eq!(lines, vec![2, 4, 6]);
+		assert!(error.diagnostics().iter().all(|diagnostic| diagnostic.code == Some("E010")));
+		root.children.truncate(1);
+		root.children[0].children.clear();
+		assert!(validate(&root).is_ok());
+	}
+
+	#[test]
+	fn test_program() {
+		let source = "@ Builds it\nname = \"doit\"\nbuild {\n\t# Comment\n\t$ echo $name\n\texit 1 + 2\n}\n";
+		let program = Program::parse("do.it", source).unwrap();
+		assert_eq!(program.help.as_deref().map(str::trim), Some("Builds it"));
+		assert_eq!(program.items.len(), 2);
+		let Item::Statement(statement) = &program.items[0] else { panic!("expected a statement") };
+		assert!(matches!(&statement.kind, StatementKind::Assign { name, .. } if name == "name"));
+		let target = program.targets().next().unwrap();
+		assert_eq!(target.name, "build");
+		assert!(matches!(&target.statements[0].kind, StatementKind::Comment(comment) if comment.trim() == "Comment"));
+		let StatementKind::Script { kind, script } = &target.statements[1].kind else { panic!("expected a script") };
+		assert_eq!((kind, script.trim()), (&ScriptKind::Shell, "echo $name"));
+		let StatementKind::Exit(Some(expression)) = &target.statements[2].kind else { panic!("expected an exit") };
+		let tokens: Vec<&str> = expression.tokens.iter().filter_map(|token| token.value.as_deref()).collect();
+		assert_eq!(tokens, vec!["1", "+", "2"]);
+	}
+
+	#[test]
+	fn test_program_errors() {
+		let mut root = Node::single(NodeType::ROOT, Token::sym(TokenType::SOF));
+		root.children = vec![Node::single(NodeType::ASSIGN, Token::val(TokenType::ASSIGN, Some("my_var".to_string())))];
+		let error = Program::new("do.it", root).err().unwrap();
+		assert!(matches!(error, DoitError::Semantic(_)));
+		assert_eq!(error.diagnostics()[0].code, Some("E006"));
+		let mut root = Node::single(NodeType::ROOT, Token::sym(TokenType::SOF));
+		root.children = vec![Node::single(NodeType::ASSIGN, Token::sym(TokenType::ASSIGN))];
+		let error = Program::new("do.it", root).err().unwrap();
+		assert_eq!(error.diagnostics()[0].code, Some("E007"));
+	}
+}
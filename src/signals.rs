@@ -0,0 +1,200 @@
+use std::{
+	ffi::c_int,
+	io, mem,
+	os::unix::process::{CommandExt, ExitStatusExt},
+	process::{Child, Command, ExitStatus},
+	ptr,
+	sync::atomic::{AtomicI32, Ordering},
+};
+
+// Numbered alike on every unix
+const SIGHUP: c_int = 1;
+const SIGINT: c_int = 2;
+const SIGQUIT: c_int = 3;
+const SIGABRT: c_int = 6;
+const SIGKILL: c_int = 9;
+const SIGSEGV: c_int = 11;
+const SIGPIPE: c_int = 13;
+const SIGALRM: c_int = 14;
+const SIGTERM: c_int = 15;
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+const SIGTTOU: c_int = 22;
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+const SIGTTOU: c_int = 27;
+const SIG_IGN: usize = 1;
+
+/** The same names that `::doit::signal_name` prints */
+const NAMES: [(c_int, &str); 9] = [
+	(SIGHUP, "SIGHUP"),
+	(SIGINT, "SIGINT"),
+	(SIGQUIT, "SIGQUIT"),
+	(SIGABRT, "SIGABRT"),
+	(SIGKILL, "SIGKILL"),
+	(SIGSEGV, "SIGSEGV"),
+	(SIGPIPE, "SIGPIPE"),
+	(SIGALRM, "SIGALRM"),
+	(SIGTERM, "SIGTERM"),
+];
+
+/** `struct sigaction`, whose layout and `SA_RESTART` differ between the C libraries */
+#[cfg(all(target_os = "linux", not(any(target_arch = "mips", target_arch = "mips64"))))]
+#[repr(C)]
+struct SigAction {
+	handler: usize,
+	mask: [u64; 16],
+	flags: c_int,
+	restorer: usize,
+}
+#[cfg(all(target_os = "linux", any(target_arch = "mips", target_arch = "mips64")))]
+#[repr(C)]
+struct SigAction {
+	flags: c_int,
+	handler: usize,
+	mask: [u64; 16],
+}
+#[cfg(target_os = "linux")]
+const SA_RESTART: c_int = 0x10000000;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[repr(C)]
+struct SigAction {
+	handler: usize,
+	mask: u32,
+	flags: c_int,
+}
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+#[repr(C)]
+struct SigAction {
+	handler: usize,
+	flags: c_int,
+	mask: [u32; 4],
+}
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly"))]
+const SA_RESTART: c_int = 0x0002;
+#[cfg(not(any(
+	target_os = "linux",
+	target_os = "macos",
+	target_os = "ios",
+	target_os = "freebsd",
+	target_os = "dragonfly"
+)))]
+compile_error!("doit does not know the layout of `struct sigaction` on this platform");
+
+unsafe extern "C" {
+	fn sigaction(signal: c_int, action: *const SigAction, previous: *mut SigAction) -> c_int;
+	fn kill(pid: c_int, signal: c_int) -> c_int;
+	fn isatty(fd: c_int) -> c_int;
+	fn getpgrp() -> c_int;
+	fn tcgetpgrp(fd: c_int) -> c_int;
+	fn tcsetpgrp(fd: c_int, group: c_int) -> c_int;
+}
+
+/**
+Installs `handler` for a signal with `sigaction`, returning the action it replaced. Unlike `signal`, the handler is
+never reset once it ran and interrupted system calls are restarted, on every platform alike.
+*/
+fn set_handler(signal: c_int, handler: usize) -> SigAction {
+	unsafe {
+		// An empty mask and no flags are all zeroes
+		let mut action: SigAction = mem::zeroed();
+		action.handler = handler;
+		action.flags = SA_RESTART;
+		let mut previous: SigAction = mem::zeroed();
+		sigaction(signal, &action, &mut previous);
+		previous
+	}
+}
+
+fn restore_handler(signal: c_int, previous: &SigAction) {
+	unsafe { sigaction(signal, previous, ptr::null_mut()) };
+}
+
+/** The process group of the running child, which the signals that doit receives are forwarded to */
+static GROUP: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward(signal: c_int) {
+	let group = GROUP.load(Ordering::SeqCst);
+	if group > 0 {
+		unsafe { kill(-group, signal) };
+	}
+}
+
+/**
+Runs a command in its own process group, calling `during` once it started. While it runs, the group owns the
+terminal so that Ctrl-C reaches the command directly, and a SIGINT or SIGTERM sent to doit is forwarded to it.
+The command is dropped before `during`, which closes doit's ends of the pipes it was given.
+*/
+pub fn run(mut command: Command, during: impl FnOnce(&mut Child)) -> io::Result<ExitStatus> {
+	// Installed first so the child inherits a handler, which exec resets, rather than a signal that doit ignores
+	let forward = forward as extern "C" fn(c_int) as usize;
+	let handlers = [SIGINT, SIGTERM].map(|number| (number, set_handler(number, forward)));
+	let status = command.process_group(0).spawn().and_then(|mut child| {
+		drop(command);
+		let group = child.id() as c_int;
+		GROUP.store(group, Ordering::SeqCst);
+		let owner = hand_terminal(group);
+		during(&mut child);
+		let status = child.wait();
+		if let Some(owner) = owner {
+			hand_terminal(owner);
+		}
+		status
+	});
+	for (number, handler) in handlers {
+		restore_handler(number, &handler);
+	}
+	GROUP.store(0, Ordering::SeqCst);
+	status
+}
+
+/** Makes `group` the foreground group of the terminal, returning the group it took over from. */
+fn hand_terminal(group: c_int) -> Option<c_int> {
+	unsafe {
+		let owner = tcgetpgrp(0);
+		// Only doit's own group may hand over the terminal, which is not doit's group when run in the background
+		if isatty(0) == 0 || (owner != getpgrp() && group != getpgrp()) {
+			return None;
+		}
+		// A background group that takes the terminal back is stopped by SIGTTOU unless it ignores it
+		let handler = set_handler(SIGTTOU, SIG_IGN);
+		tcsetpgrp(0, group);
+		restore_handler(SIGTTOU, &handler);
+		Some(owner)
+	}
+}
+
+/** The exit code of a process, or 128 plus the signal that killed it as shells report it. */
+pub fn exit_code(status: &ExitStatus) -> u8 {
+	match status.signal() {
+		Some(signal) => (128 + signal) as u8,
+		None => (status.code().unwrap_or(1) & 0xff) as u8,
+	}
+}
+
+pub fn name(signal: c_int) -> String {
+	let name = NAMES.iter().find(|(number, _)| *number == signal).map(|(_, name)| name.to_string());
+	name.unwrap_or(format!("signal {signal}"))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+
+	use super::{SA_RESTART, SIG_IGN, SIGALRM, exit_code, name, set_handler};
+
+	#[test]
+	fn test_exit_code() {
+		assert_eq!(exit_code(&ExitStatus::from_raw(3 << 8)), 3);
+		assert_eq!(exit_code(&ExitStatus::from_raw(2)), 130);
+		assert_eq!(exit_code(&ExitStatus::from_raw(15)), 143);
+		assert_eq!(name(15), "SIGTERM");
+		assert_eq!(name(64), "signal 64");
+	}
+
+	#[test]
+	fn test_set_handler() {
+		let previous = set_handler(SIGALRM, SIG_IGN);
+		let ignored = set_handler(SIGALRM, previous.handler);
+		assert_eq!(ignored.handler, SIG_IGN);
+		assert_eq!(ignored.flags & SA_RESTART, SA_RESTART);
+	}
+}
@@ -0,0 +1,222 @@
+use std::{
+	env,
+	io::{ErrorKind, Write},
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+};
+
+use crate::error::DoitError;
+
+/** Overrides the C++ compiler, ie. `CXX=clang++`, which may be a command with arguments like `CXX="ccache g++"` */
+pub const CXX_ENV: &str = "CXX";
+/** Replaces the default optimisation flags, ie. `CXXFLAGS="-O0 -g"` */
+pub const CXXFLAGS_ENV: &str = "CXXFLAGS";
+/** Compilers that are tried in order when `CXX` is not set */
+const DEFAULT_COMPILERS: &[&str] = &["g++", "clang++"];
+const DEFAULT_FLAGS: &[&str] = &["-O2"];
+/** Fails to compile on anything older than C++20 */
+const CPP20_PROBE: &str = "#if __cplusplus < 202002L\n#error C++20 is not supported\n#endif\n";
+
+/** The C++ compiler and the flags that the generated source is compiled with. */
+#[derive(Debug, PartialEq)]
+pub struct Toolchain {
+	pub compiler: String,
+	/** The words of `CXX` after the program, ie. `g++` of `CXX="ccache g++"`, which come before any other argument */
+	pub compiler_args: Vec<String>,
+	pub flags: Vec<String>,
+}
+
+impl Toolchain {
+	pub fn new(compiler: &str, cxxflags: Option<&str>) -> Toolchain {
+		Toolchain {
+			compiler: compiler.to_string(),
+			compiler_args: vec![],
+			flags: match cxxflags {
+				Some(flags) => flags.split_whitespace().map(str::to_string).collect(),
+				None => DEFAULT_FLAGS.iter().map(|flag| flag.to_string()).collect(),
+			},
+		}
+	}
+
+	/** Picks the compiler from `CXX`, or the first of g++ and clang++ found on the PATH. */
+	pub fn from_env() -> Result<Toolchain, DoitError> {
+		let cxxflags = env::var(CXXFLAGS_ENV).ok();
+		if let Some(cxx) = env::var(CXX_ENV).ok().filter(|cxx| !cxx.trim().is_empty()) {
+			let Some(mut words) = split_words(&cxx) else {
+				let message = format!("{CXX_ENV} is set to '{cxx}', which has an unterminated quote.");
+				return Err(DoitError::Toolchain(message));
+			};
+			return match find_executable(&words.remove(0)) {
+				Some(path) => Ok(Toolchain {
+					compiler_args: words,
+					..Toolchain::new(&path.to_string_lossy(), cxxflags.as_deref())
+				}),
+				None => Err(DoitError::Toolchain(format!(
+					"{CXX_ENV} is set to '{cxx}', but no such compiler could be found. Fix or unset {CXX_ENV} to use the default g++ or clang++."
+				))),
+			};
+		}
+		DEFAULT_COMPILERS
+			.iter()
+			.find_map(|compiler| find_executable(compiler))
+			.map(|path| Toolchain::new(&path.to_string_lossy(), cxxflags.as_deref()))
+			.ok_or_else(|| {
+				DoitError::Toolchain(format!(
+					"Could not find a C++ compiler. doit needs g++ or clang++ with C++20 support on the PATH \
+					(ie. `sudo apt install g++`), or {CXX_ENV} set to the compiler to use."
+				))
+			})
+	}
+
+	/** Whether the compiler is clang, including when `CXX` runs it through a wrapper such as ccache. */
+	fn is_clang(&self) -> bool {
+		[&self.compiler]
+			.into_iter()
+			.chain(self.compiler_args.iter().filter(|arg| !arg.starts_with('-')))
+			.any(|word| Path::new(word).file_name().is_some_and(|name| name.to_string_lossy().contains("clang")))
+	}
+
+	/** Every argument passed to the compiler, minus the input and output paths. */
+	pub fn args(&self) -> Vec<String> {
+		let mut args = self.compiler_args.clone();
+		args.push("--std=c++20".to_string());
+		// Plain diagnostics are easier to map back onto the do.it source
+		if self.is_clang() {
+			args.extend(["-fno-caret-diagnostics", "-fno-color-diagnostics"].map(str::to_string));
+		} else {
+			args.extend(["-fno-diagnostics-show-caret", "-fdiagnostics-color=never"].map(str::to_string));
+		}
+		args.extend(self.flags.iter().cloned());
+		args
+	}
+
+	/** The compiler followed by its arguments, which is what the cache key is built from. */
+	pub fn command(&self) -> Vec<String> {
+		let mut command = vec![self.compiler.clone()];
+		command.extend(self.args());
+		command
+	}
+
+	/** Checks that the compiler runs and supports C++20, before it is handed the generated source. */
+	pub fn probe(&self) -> Result<(), DoitError> {
+		let failed = |err: std::io::Error| match err.kind() {
+			ErrorKind::NotFound => DoitError::Toolchain(format!("Could not run the C++ compiler '{}': not found", self.compiler)),
+			_ => DoitError::Toolchain(format!("Could not run the C++ compiler '{}': {err}", self.compiler)),
+		};
+		let version = Command::new(&self.compiler).args(&self.compiler_args).arg("--version").output().map_err(failed)?;
+		let version = String::from_utf8_lossy(&version.stdout).lines().next().unwrap_or_default().to_string();
+
+		let mut child = Command::new(&self.compiler)
+			.args(&self.compiler_args)
+			.args(["--std=c++20", "-x", "c++", "-fsyntax-only", "-"])
+			.stdin(Stdio::piped())
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.map_err(failed)?;
+		if let Some(mut stdin) = child.stdin.take() {
+			stdin.write_all(CPP20_PROBE.as_bytes()).map_err(failed)?;
+		}
+		if !child.wait().map_err(failed)?.success() {
+			return Err(DoitError::Toolchain(format!(
+				"The C++ compiler '{}' ({version}) does not support C++20. Install g++ 10 or clang++ 10 or newer, or set {CXX_ENV} to a compiler that does.",
+				self.compiler
+			)));
+		}
+		Ok(())
+	}
+}
+
+/**
+Whether `CXX` is unset and neither of the default compilers is on the PATH, which is the only toolchain error that the
+automatic backend falls back to the interpreter on. Any other one is a compiler that was asked for but is broken.
+*/
+pub fn compiler_missing() -> bool {
+	env::var(CXX_ENV).ok().is_none_or(|cxx| cxx.trim().is_empty())
+		&& DEFAULT_COMPILERS.iter().all(|compiler| find_executable(compiler).is_none())
+}
+
+/** Resolves a program name against the PATH the same way the shell would, or checks a path directly. */
+fn find_executable(name: &str) -> Option<PathBuf> {
+	if name.contains('/') {
+		return Some(PathBuf::from(name)).filter(|path| path.is_file());
+	}
+	env::split_paths(&env::var_os("PATH")?).map(|directory| directory.join(name)).find(|path| path.is_file())
+}
+
+/**
+Splits a command into words the way the shell would, where quotes and backslashes keep whitespace within a word. Returns
+`None` if a quote is never closed.
+*/
+fn split_words(command: &str) -> Option<Vec<String>> {
+	let mut words: Vec<String> = vec![];
+	let mut word: Option<String> = None;
+	let mut chars = command.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			c if c.is_whitespace() => words.extend(word.take()),
+			'\'' => {
+				let quoted = word.get_or_insert_default();
+				loop {
+					match chars.next()? {
+						'\'' => break,
+						c => quoted.push(c),
+					}
+				}
+			}
+			'"' => {
+				let quoted = word.get_or_insert_default();
+				loop {
+					match chars.next()? {
+						'"' => break,
+						'\\' => match chars.next()? {
+							c @ ('"' | '\\' | '$' | '`') => quoted.push(c),
+							c => quoted.extend(['\\', c]),
+						},
+						c => quoted.push(c),
+					}
+				}
+			}
+			'\\' => word.get_or_insert_default().extend(chars.next()),
+			c => word.get_or_insert_default().push(c),
+		}
+	}
+	words.extend(word);
+	Some(words)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Toolchain, find_executable, split_words};
+
+	#[test]
+	fn test_toolchain_args() {
+		let gcc = Toolchain::new("/usr/bin/g++", None);
+		assert_eq!(gcc.args(), vec!["--std=c++20", "-fno-diagnostics-show-caret", "-fdiagnostics-color=never", "-O2"]);
+		let clang = Toolchain::new("/usr/bin/clang++-17", Some("-O0  -g"));
+		assert_eq!(clang.args(), vec!["--std=c++20", "-fno-caret-diagnostics", "-fno-color-diagnostics", "-O0", "-g"]);
+		assert_eq!(clang.command()[0], "/usr/bin/clang++-17");
+		assert_eq!(Toolchain::new("g++", Some("")).flags, Vec::<String>::new());
+		let wrapped =
+			Toolchain { compiler_args: vec!["clang++".to_string()], ..Toolchain::new("/usr/bin/ccache", None) };
+		assert_eq!(wrapped.command()[..3], ["/usr/bin/ccache", "clang++", "--std=c++20"]);
+		assert_eq!(wrapped.args()[2], "-fno-caret-diagnostics");
+	}
+
+	#[test]
+	fn test_split_words() {
+		assert_eq!(split_words("  ccache g++ -m32 "), Some(vec!["ccache".into(), "g++".into(), "-m32".into()]));
+		assert_eq!(
+			split_words(r#"'/opt/my tools/g++' "-DNAME=\"a b\"" a\ b ''"#),
+			Some(vec!["/opt/my tools/g++".into(), "-DNAME=\"a b\"".into(), "a b".into(), "".into()])
+		);
+		assert_eq!(split_words("g++ 'unterminated"), None);
+	}
+
+	#[test]
+	fn test_find_executable() {
+		assert!(find_executable("sh").is_some());
+		assert!(find_executable("/bin/sh").is_some());
+		assert!(find_executable("doit-no-such-compiler").is_none());
+	}
+}
@@ -1,8 +1,62 @@
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
 
-use std::hash::{DefaultHasher, Hash, Hasher};
+/**
+64-bit FNV-1a hasher. Unlike `std::hash::DefaultHasher`, its output is fixed by the algorithm itself, so hashes
+written to disk by one build of doit stay valid for every other build on every platform.
+*/
+pub struct StableHasher {
+	state: u64,
+}
+
+impl StableHasher {
+	pub fn new() -> StableHasher {
+		StableHasher { state: FNV_OFFSET_BASIS }
+	}
+	pub fn write(&mut self, bytes: &[u8]) {
+		for byte in bytes {
+			self.state ^= *byte as u64;
+			self.state = self.state.wrapping_mul(FNV_PRIME);
+		}
+	}
+	/** Writes a length prefixed field, so that moving bytes from one field to the next changes the hash. */
+	pub fn write_field(&mut self, field: &str) {
+		self.write(&(field.len() as u64).to_le_bytes());
+		self.write(field.as_bytes());
+	}
+	pub fn finish(&self) -> u64 {
+		self.state
+	}
+}
+
+impl Default for StableHasher {
+	fn default() -> Self {
+		StableHasher::new()
+	}
+}
 
-pub fn calculate_hash<T: Hash>(data: &T) -> u64 {
-	let mut hasher = DefaultHasher::new();
-	data.hash(&mut hasher);
+pub fn calculate_hash(fields: &[&str]) -> u64 {
+	let mut hasher = StableHasher::new();
+	fields.iter().for_each(|field| hasher.write_field(field));
 	hasher.finish()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{StableHasher, calculate_hash};
+
+	#[test]
+	fn test_stable_hasher() {
+		// Reference values of the FNV-1a 64 specification
+		let mut hasher = StableHasher::new();
+		assert_eq!(hasher.finish(), 0xcbf2_9ce4_8422_2325);
+		hasher.write(b"a");
+		assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+		let mut hasher = StableHasher::new();
+		hasher.write(b"foobar");
+		assert_eq!(hasher.finish(), 0x8594_4171_f739_67e8);
+
+		assert_eq!(calculate_hash(&["ab", "c"]), calculate_hash(&["ab", "c"]));
+		assert_ne!(calculate_hash(&["ab", "c"]), calculate_hash(&["a", "bc"]));
+	}
+}
@@ -1,5 +1,3 @@
-#![allow(dead_code)]
-
 pub fn debug(message: &str) {
 	if cfg!(debug_assertions) {
 		println!("\x1b[42m DBG \x1b[0m \x1b[32m{message}\x1b[0m")
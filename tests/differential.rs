@@ -0,0 +1,227 @@
+use std::{
+	env, fs,
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+	thread,
+	time::Duration,
+};
+
+/** Runs doit on a do.it file, returning its stdout without the debug logging and its exit code. */
+fn doit(cache: &Path, file: &Path, args: &[&str]) -> (String, i32) {
+	let mut command = Command::new(env!("CARGO_BIN_EXE_doit"));
+	command.env("XDG_CACHE_HOME", cache).env_remove("DOIT_CACHE_DIR").arg("-t").arg(file).args(args);
+	run(&mut command)
+}
+
+fn run(command: &mut Command) -> (String, i32) {
+	let output = command.output().expect("failed to run doit");
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	let stdout: Vec<&str> = stdout.split_inclusive('\n').filter(|line| !line.contains(" DBG ")).collect();
+	(stdout.concat(), output.status.code().unwrap_or(-1))
+}
+
+/** Runs doit with `-x`, returning the trace it prints to stderr without the durations, which differ between runs. */
+fn trace(cache: &Path, file: &Path, args: &[&str]) -> String {
+	let mut command = Command::new(env!("CARGO_BIN_EXE_doit"));
+	command.env("XDG_CACHE_HOME", cache).env_remove("DOIT_CACHE_DIR").arg("-t").arg(file).arg("-x").args(args);
+	let output = command.output().expect("failed to run doit");
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	let lines = stderr.lines().map(|line| match line.find(" after ") {
+		Some(index) if line.ends_with("s\x1b[0m") => format!("{} after <duration>", &line[..index]),
+		_ => line.to_string(),
+	});
+	lines.collect::<Vec<String>>().join("\n")
+}
+
+/** Runs doit with `--junit`, returning its stdout and the report without the durations. */
+fn junit(cache: &Path, file: &Path, args: &[&str]) -> (String, String) {
+	let report = cache.join("report.xml");
+	let (stdout, _) = doit(cache, file, &[&["--junit", report.to_str().unwrap()], args].concat());
+	let report = fs::read_to_string(&report).unwrap_or_default();
+	let masked = report.split(" time=\"").enumerate().map(|(index, part)| match (index, part.find('"')) {
+		(1.., Some(end)) => format!(" time=\"<duration>{}", &part[end..]),
+		_ => part.to_string(),
+	});
+	(stdout, masked.collect())
+}
+
+fn test_files() -> Vec<PathBuf> {
+	let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-files");
+	let mut files: Vec<PathBuf> = fs::read_dir(directory)
+		.unwrap()
+		.map(|entry| entry.unwrap().path())
+		.filter(|path| path.extension().is_some_and(|ext| ext == "it"))
+		.collect();
+	files.sort();
+	files
+}
+
+/**
+Every target of every do.it file in `test-files/` must behave identically when compiled, when interpreted and when
+exported to a shell script.
+*/
+#[test]
+fn test_backends_agree() {
+	if Command::new(env::var("CXX").unwrap_or("g++".to_string())).arg("--version").output().is_err() {
+		eprintln!("skipping: no C++ compiler available");
+		return;
+	}
+	let cache = env::temp_dir().join(format!("doit-differential-{}", std::process::id()));
+	fs::create_dir_all(&cache).unwrap();
+	for file in test_files() {
+		let (targets, code) = doit(&cache, &file, &["--targets"]);
+		assert_eq!(code, 0, "{}", file.display());
+		let (script, code) = doit(&cache, &file, &["--emit", "sh"]);
+		assert_eq!(code, 0, "{}", file.display());
+		let exported = cache.join("tasks.sh");
+		fs::write(&exported, script).unwrap();
+		let mut runs: Vec<Vec<&str>> = vec![vec![], vec!["no-such-target"]];
+		for target in targets.lines() {
+			runs.push(vec![target]);
+			runs.push(vec![target, "a", "b", "c", "d", "e"]);
+		}
+		for args in runs {
+			let compiled = doit(&cache, &file, &[&["--backend=cpp"], args.as_slice()].concat());
+			let interpreted = doit(&cache, &file, &[&["--backend=interp"], args.as_slice()].concat());
+			let shell = run(Command::new("sh").arg(&exported).args(&args));
+			assert_eq!(compiled, interpreted, "{} {:?}", file.display(), args);
+			assert_eq!(compiled, shell, "{} {:?} (exported to sh)", file.display(), args);
+		}
+		for target in targets.lines() {
+			let args = ["-n", target, "a", "b"];
+			let compiled = doit(&cache, &file, &[&["--backend=cpp"], args.as_slice()].concat());
+			let interpreted = doit(&cache, &file, &[&["--backend=interp"], args.as_slice()].concat());
+			assert_eq!(compiled, interpreted, "{} {:?}", file.display(), args);
+			let compiled = trace(&cache, &file, &["--backend=cpp", target, "a"]);
+			let interpreted = trace(&cache, &file, &["--backend=interp", target, "a"]);
+			assert_eq!(compiled, interpreted, "{} -x {}", file.display(), target);
+			let compiled = junit(&cache, &file, &["--backend=cpp", target, "a"]);
+			let interpreted = junit(&cache, &file, &["--backend=interp", target, "a"]);
+			assert_eq!(compiled, interpreted, "{} --junit {}", file.display(), target);
+		}
+	}
+	let _ = fs::remove_dir_all(&cache);
+}
+
+/**
+A script that is killed stops its target with 128 plus the signal, and a signal sent to doit is forwarded to the
+running script, whether the targets are compiled or interpreted.
+*/
+#[test]
+fn test_signals() {
+	if Command::new(env::var("CXX").unwrap_or("g++".to_string())).arg("--version").output().is_err() {
+		eprintln!("skipping: no C++ compiler available");
+		return;
+	}
+	let cache = env::temp_dir().join(format!("doit-signals-{}", std::process::id()));
+	fs::create_dir_all(&cache).unwrap();
+	let file = cache.join("do.it");
+	let source = "killed {\n\t$ echo before\n\t$ kill -TERM $$$$\n\t$ echo after\n}\n\
+		sleeps {\n\t$ sleep 5\n\t$ echo after\n}\n";
+	fs::write(&file, source).unwrap();
+	for backend in ["--backend=cpp", "--backend=interp"] {
+		assert_eq!(doit(&cache, &file, &[backend, "killed"]), ("before\n".to_string(), 143), "{backend}");
+		let mut command = Command::new(env!("CARGO_BIN_EXE_doit"));
+		command.env("XDG_CACHE_HOME", &cache).env_remove("DOIT_CACHE_DIR").arg("-t").arg(&file).arg(backend);
+		let child = command.arg("sleeps").stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+		thread::sleep(Duration::from_millis(500));
+		Command::new("kill").arg("-INT").arg(child.id().to_string()).status().unwrap();
+		let output = child.wait_with_output().unwrap();
+		assert_eq!(output.status.code(), Some(130), "{backend}");
+		assert!(String::from_utf8_lossy(&output.stderr).contains("killed by SIGINT"), "{backend}");
+		assert!(!String::from_utf8_lossy(&output.stdout).contains("after"), "{backend}");
+	}
+	let _ = fs::remove_dir_all(&cache);
+}
+
+/** Both backends run their scripts with the shell and options of `DOIT_SHELL`. */
+#[test]
+fn test_shell() {
+	if Command::new(env::var("CXX").unwrap_or("g++".to_string())).arg("--version").output().is_err() {
+		eprintln!("skipping: no C++ compiler available");
+		return;
+	}
+	let cache = env::temp_dir().join(format!("doit-shell-{}", std::process::id()));
+	fs::create_dir_all(&cache).unwrap();
+	let file = cache.join("do.it");
+	fs::write(&file, "strict {\n\t$ false; echo after\n}\n").unwrap();
+	for backend in ["--backend=cpp", "--backend=interp"] {
+		let mut command = Command::new(env!("CARGO_BIN_EXE_doit"));
+		command.env("XDG_CACHE_HOME", &cache).env_remove("DOIT_CACHE_DIR").arg("-t").arg(&file);
+		assert_eq!(run(command.arg(backend).arg("strict")), ("after\n".to_string(), 0), "{backend}");
+		assert_eq!(run(command.env("DOIT_SHELL", "sh -e")), (String::new(), 1), "{backend}");
+	}
+	let _ = fs::remove_dir_all(&cache);
+}
+
+/** An invalid expression is rejected with the same diagnostic and exit code by every backend, whichever target runs. */
+#[test]
+fn test_invalid_expressions() {
+	if Command::new(env::var("CXX").unwrap_or("g++".to_string())).arg("--version").output().is_err() {
+		eprintln!("skipping: no C++ compiler available");
+		return;
+	}
+	let cache = env::temp_dir().join(format!("doit-invalid-{}", std::process::id()));
+	fs::create_dir_all(&cache).unwrap();
+	let file = cache.join("do.it");
+	let sources = [
+		"greeting = \"Hello, \" + \"world\"\nok {\n\t$ echo ok\n}\n",
+		"ok {\n\t$ echo ok\n}\nbad {\n\tcount = 1 + \"a\"\n}\n",
+		"ok {\n\t$ echo ok\n}\nbad {\n\tname = \"a\"\n\tname = 1 - name\n}\n",
+		"ok {\n\t$ echo ok\n}\nbad {\n\texit \"failed\"\n}\n",
+	];
+	for source in sources {
+		fs::write(&file, source).unwrap();
+		for backend in [&["--backend=cpp"][..], &["--backend=interp"], &["--emit", "sh"]] {
+			let mut command = Command::new(env!("CARGO_BIN_EXE_doit"));
+			command.env("XDG_CACHE_HOME", &cache).env_remove("DOIT_CACHE_DIR").arg("-t").arg(&file);
+			let output = command.args(backend).arg("ok").output().unwrap();
+			assert_eq!(output.status.code(), Some(67), "{backend:?} {source:?}");
+			assert!(String::from_utf8_lossy(&output.stderr).contains("error[E006]"), "{backend:?} {source:?}");
+		}
+	}
+	let _ = fs::remove_dir_all(&cache);
+}
+
+/** The options that doit hands to a run are not inherited by its scripts, such as another doit that they run. */
+#[test]
+fn test_run_options_not_inherited() {
+	if Command::new(env::var("CXX").unwrap_or("g++".to_string())).arg("--version").output().is_err() {
+		eprintln!("skipping: no C++ compiler available");
+		return;
+	}
+	let cache = env::temp_dir().join(format!("doit-options-{}", std::process::id()));
+	fs::create_dir_all(&cache).unwrap();
+	let file = cache.join("do.it");
+	fs::write(&file, "env {\n\t$ env | grep -E '^DOIT_(DRY_RUN|VERBOSE|CAPTURE|EVENTS)=' || echo clean\n}\n").unwrap();
+	let report = cache.join("report.xml");
+	for backend in ["--backend=cpp", "--backend=interp"] {
+		let args = ["-x", "--junit", report.to_str().unwrap(), backend, "env"];
+		assert_eq!(doit(&cache, &file, &args), ("clean\n".to_string(), 0), "{backend}");
+	}
+	let _ = fs::remove_dir_all(&cache);
+}
+
+/** Python scripts read the stdin of doit, and run with the python of `DOIT_PYTHON`. */
+#[test]
+fn test_python() {
+	if Command::new(env::var("CXX").unwrap_or("g++".to_string())).arg("--version").output().is_err() {
+		eprintln!("skipping: no C++ compiler available");
+		return;
+	}
+	let cache = env::temp_dir().join(format!("doit-python-{}", std::process::id()));
+	fs::create_dir_all(&cache).unwrap();
+	let file = cache.join("do.it");
+	fs::write(&file, "ask {\n\t% print(input().upper(), __debug__)\n}\n").unwrap();
+	let input = cache.join("input");
+	fs::write(&input, "hello\n").unwrap();
+	for backend in ["--backend=cpp", "--backend=interp"] {
+		let mut command = Command::new(env!("CARGO_BIN_EXE_doit"));
+		command.env("XDG_CACHE_HOME", &cache).env_remove("DOIT_CACHE_DIR").arg("-t").arg(&file);
+		command.arg(backend).arg("ask").stdin(fs::File::open(&input).unwrap());
+		assert_eq!(run(&mut command), ("HELLO True\n".to_string(), 0), "{backend}");
+		command.env("DOIT_PYTHON", "python3 -O").stdin(fs::File::open(&input).unwrap());
+		assert_eq!(run(&mut command), ("HELLO False\n".to_string(), 0), "{backend}");
+	}
+	let _ = fs::remove_dir_all(&cache);
+}